@@ -0,0 +1,187 @@
+//! Contact cards: our address and identity key packed into
+//! a short armored text, so that they can be handed to a colleague
+//! over any channel and imported without editing the config.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bincode::{self, Options};
+use qrcode::{EcLevel, QrCode, render::unicode::Dense1x2};
+use rsa::RsaPublicKey;
+use rsa::pkcs1::{DecodeRsaPublicKey, EncodeRsaPublicKey};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, ErrCode, convert_err};
+
+const HEADER: &str = "-----BEGIN SIMI CONTACT-----";
+const FOOTER: &str = "-----END SIMI CONTACT-----";
+
+/// Card format version
+const CARD_VERSION: u16 = 1;
+
+/// Length of the checksum appended to the encoded card
+const CHECKSUM_LEN: usize = 4;
+
+/// Length of a line of the armored text
+const LINE_LEN: usize = 64;
+
+pub struct Card {
+    /// Address in `ip:port` format
+    pub addr: String,
+    pub pkey: RsaPublicKey,
+}
+
+/// Encoded form of a card; the key is stored in PKCS#1 DER,
+/// which is more compact than its serde representation
+#[derive(Serialize, Deserialize)]
+struct CardRepr {
+    version: u16,
+    addr: String,
+    pkey: Vec<u8>,
+}
+
+fn serializer() -> impl Options {
+    bincode::DefaultOptions::new().with_little_endian()
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(bytes)[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+fn ill_formed() -> Error {
+    Error::new(ErrCode::WrongArgs, "not a valid contact card".to_owned())
+}
+
+impl Card {
+    pub fn new(addr: String, pkey: RsaPublicKey) -> Self {
+        Self { addr, pkey }
+    }
+
+    /// Encodes the card as armored text
+    pub fn armor(&self) -> Result<String, Error> {
+        let repr = CardRepr {
+            version: CARD_VERSION,
+            addr: self.addr.clone(),
+            pkey: self.pkey.to_pkcs1_der().map_err(|e| convert_err(e, ErrCode::Serial))?.into_vec(),
+        };
+        let mut raw = serializer()
+            .serialize(&repr)
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        raw.extend_from_slice(&checksum(&raw));
+        let body = STANDARD.encode(raw);
+        let mut text = format!("{HEADER}\n");
+        for line in body.as_bytes().chunks(LINE_LEN) {
+            text.push_str(std::str::from_utf8(line).unwrap());
+            text.push('\n');
+        }
+        text.push_str(FOOTER);
+        Ok(text)
+    }
+
+    /// Decodes a card from armored text.
+    ///
+    /// Header and footer lines are optional, so the body
+    /// pasted alone is accepted as well.
+    pub fn from_armor(text: &str) -> Result<Self, Error> {
+        let body = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| *line != HEADER && *line != FOOTER)
+            .collect::<String>();
+        let raw = STANDARD.decode(body).map_err(|_| ill_formed())?;
+        if raw.len() < CHECKSUM_LEN {
+            return Err(ill_formed());
+        }
+        let (raw, sum) = raw.split_at(raw.len() - CHECKSUM_LEN);
+        if checksum(raw) != sum {
+            return Err(Error::new(ErrCode::WrongArgs, "contact card is damaged".to_owned()));
+        }
+        let repr: CardRepr = serializer().deserialize(raw).map_err(|_| ill_formed())?;
+        if repr.version != CARD_VERSION {
+            return Err(Error::new(
+                ErrCode::WrongArgs,
+                format!("unsupported contact card version {}", repr.version)));
+        }
+        let pkey = RsaPublicKey::from_pkcs1_der(&repr.pkey).map_err(|_| ill_formed())?;
+        Ok(Self { addr: repr.addr, pkey })
+    }
+
+    /// Renders the armored card as a QR code printable in a terminal
+    pub fn qr(&self) -> Result<String, Error> {
+        let code = QrCode::with_error_correction_level(self.armor()?, EcLevel::L)
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        Ok(code.render::<Dense1x2>().quiet_zone(true).build())
+    }
+}
+
+/// Returns `true` if `line` ends an armored card
+pub fn is_footer(line: &str) -> bool {
+    line.trim() == FOOTER
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use rsa::RsaPrivateKey;
+
+    use super::*;
+
+    fn key() -> RsaPublicKey {
+        RsaPublicKey::from(RsaPrivateKey::new(&mut thread_rng(), 512).unwrap())
+    }
+
+    /// Body of an armored card, decoded
+    fn decoded(text: &str) -> Vec<u8> {
+        let body: String = text.lines().filter(|line| !line.starts_with("-----")).collect();
+        STANDARD.decode(body).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let card = Card::new("[2001:db8::7]:1337".to_owned(), key());
+        let text = card.armor().unwrap();
+        assert!(text.starts_with(HEADER) && text.ends_with(FOOTER));
+        assert!(text.lines().all(|line| line.len() <= LINE_LEN));
+        let parsed = Card::from_armor(&text).unwrap();
+        assert_eq!(parsed.addr, card.addr);
+        assert_eq!(parsed.pkey, card.pkey);
+        // The body alone, as pasted without the armor lines
+        let body: String = text.lines().filter(|line| !line.starts_with("-----")).collect();
+        assert_eq!(Card::from_armor(&body).unwrap().pkey, card.pkey);
+    }
+
+    #[test]
+    fn truncated() {
+        let text = Card::new("10.0.0.1:1337".to_owned(), key()).armor().unwrap();
+        let mut lines: Vec<&str> = text.lines().collect();
+        // A line lost when copying
+        lines.remove(2);
+        assert!(Card::from_armor(&lines.join("\n")).is_err());
+        assert!(Card::from_armor(&text[..text.len() / 2]).is_err());
+        assert!(Card::from_armor("").is_err());
+    }
+
+    #[test]
+    fn corrupted() {
+        let text = Card::new("10.0.0.1:1337".to_owned(), key()).armor().unwrap();
+        // Not base64 at all
+        assert!(Card::from_armor(&text.replacen('A', "*", 1).replacen('B', "*", 1)).is_err());
+        // Valid base64 carrying a flipped bit anywhere in the card
+        let raw = decoded(&text);
+        for i in [0, raw.len() / 2, raw.len() - 1] {
+            let mut damaged = raw.clone();
+            damaged[i] ^= 1;
+            assert!(Card::from_armor(&STANDARD.encode(damaged)).is_err(), "byte {i}");
+        }
+    }
+
+    #[test]
+    fn key_mismatch() {
+        let card = Card::new("10.0.0.1:1337".to_owned(), key()).armor().unwrap();
+        let other = Card::new("10.0.0.1:1337".to_owned(), key()).armor().unwrap();
+        // Another key under the checksum of the original card
+        let (raw, other_raw) = (decoded(&card), decoded(&other));
+        let mut swapped = other_raw[..other_raw.len() - CHECKSUM_LEN].to_vec();
+        swapped.extend_from_slice(&raw[raw.len() - CHECKSUM_LEN..]);
+        let e = Card::from_armor(&STANDARD.encode(swapped)).err().unwrap();
+        assert_eq!(e.descr, "contact card is damaged");
+    }
+}
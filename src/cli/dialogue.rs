@@ -12,8 +12,9 @@ pub fn interpret(line: &str) -> Result<Command, Error> {
         match cmd {
             Some("exit") => exit(args),
             Some("secret") => secret(args),
+            Some("rekey") => rekey(args),
             Some(cmd) => 
-                Err(Error::new(ErrCode::UnknownCommand, format!("unknown command \"{}\"", cmd))),
+                Err(Error::new(ErrCode::UnknownCommand, format!("unknown command \"{cmd}\""))),
             None => Err(Error::new(ErrCode::EmptyLine, String::new())),
         }
     } else {
@@ -29,24 +30,30 @@ fn exit(mut args: Split<&str>) -> Result<Command, Error> {
     }
 }
 
+fn rekey(mut args: Split<&str>) -> Result<Command, Error> {
+    if args.next().is_some() {
+        Err(Error::new(ErrCode::WrongArgs, "usage: --rekey".to_owned()))
+    } else {
+        Ok(Command::Rekey)
+    }
+}
+
 // Temporary function implementation
 // Use clap crate for multiple arguments, if there are going to be any
 fn secret(args: Split<&str>) -> Result<Command, Error> {
-    let args = args.collect::<Vec<_>>();
-    if args.len() > 1 {
-        return Err(Error::new(ErrCode::WrongArgs, "usage: --secret [--path=/path/to/file]".to_owned()));
-    }
-    if args.len() == 0 {
-        return Ok(Command::Secret(None));
-    }
-    let mut arg = args[0].split("=");
-    let key = arg.next().unwrap();
-    if key != "--path" {
-        return Err(Error::new(ErrCode::WrongArgs, format!("unknown argument \"{}\"", key)));
+    const USAGE: &str = "usage: --secret [--path=/path/to/file] [--sign]";
+    let mut path = None;
+    let mut sign = false;
+    for arg in args {
+        let mut arg = arg.split('=');
+        match (arg.next().unwrap(), arg.next()) {
+            ("--sign", None) if !sign => sign = true,
+            ("--path", Some(val)) if path.is_none() => path = Some(val.to_owned()),
+            ("--path", None) =>
+                return Err(Error::new(ErrCode::WrongArgs, "usage: --path=/path/to/file.png".to_owned())),
+            ("--sign" | "--path", _) => return Err(Error::new(ErrCode::WrongArgs, USAGE.to_owned())),
+            (key, _) => return Err(Error::new(ErrCode::WrongArgs, format!("unknown argument \"{key}\""))),
+        }
     }
-    let path = match arg.next() {
-        None => {return Err(Error::new(ErrCode::WrongArgs, "usage: --path=/path/to/file.png".to_owned()));}
-        Some(val) => val.to_owned()
-    };
-    Ok(Command::Secret(Some(path)))
+    Ok(Command::Secret(path, sign))
 }
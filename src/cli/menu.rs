@@ -17,7 +17,10 @@ pub fn interpret(line: &str) -> Result<Command, Error> {
         Some("dial") => dial(args),
         Some("save") => save(args),
         Some("debug") => debug(args),
-        Some(cmd) => Err(Error::new(ErrCode::UnknownCommand, format!("unknown command \"{}\"", cmd))),
+        Some("passwd") => passwd(args),
+        Some("export") => export(args),
+        Some("import") => import(args),
+        Some(cmd) => Err(Error::new(ErrCode::UnknownCommand, format!("unknown command \"{cmd}\""))),
         None => Err(Error::new(ErrCode::EmptyLine, String::new()))
     }
 }
@@ -83,3 +86,28 @@ fn debug(mut args: Split<&str>) -> Result<Command, Error> {
         Ok(Command::Debug)
     }
 }
+
+fn passwd(mut args: Split<&str>) -> Result<Command, Error> {
+    if args.next().is_some() {
+        Err(Error::new(ErrCode::WrongArgs, "usage: passwd".to_owned()))
+    } else {
+        Ok(Command::Passwd)
+    }
+}
+
+fn export(args: Split<&str>) -> Result<Command, Error> {
+    let args = args.collect::<Vec<_>>();
+    match args.len() {
+        0 => Ok(Command::Export(None)),
+        1 => Ok(Command::Export(Some(args[0].to_owned()))),
+        _ => Err(Error::new(ErrCode::WrongArgs, "usage: export [ip] or export [ip:port]".to_owned())),
+    }
+}
+
+fn import(args: Split<&str>) -> Result<Command, Error> {
+    let args = args.collect::<Vec<_>>();
+    if args.len() != 1 {
+        return Err(Error::new(ErrCode::WrongArgs, "usage: import <alias>".to_owned()));
+    }
+    Ok(Command::Import(args[0].to_owned()))
+}
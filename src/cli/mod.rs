@@ -10,7 +10,12 @@ pub enum Command {
     Remove(String),
     DialIp(String),
     DialAlias(String),
-    Secret(Option<String>),
+    /// Secret with an optional path to the image and whether to sign it
+    Secret(Option<String>, bool),
     SpeakPlain(String),
     Debug,
+    Passwd,
+    Rekey,
+    Export(Option<String>),
+    Import(String),
 }
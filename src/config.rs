@@ -1,24 +1,39 @@
 use std::str::FromStr;
 use std::{collections::BTreeMap, path::PathBuf};
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
 use serde::{Serialize, Deserialize};
-use toml;
 use home::{self, home_dir};
 
+use crate::keystore::KeyType;
+use crate::proto::{HandshakeMode, same_addr};
+use crate::proto::cipher::CipherSuite;
+
 const PATH_TO_CONFIG: &str = "~/.simi/conf.toml";
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 /// Representation of current running configuration.
 ///
 /// This struct should be mutable to allow users
 /// modify their contact list in runtime.
+/// Fields missing in the file are taken from `Config::default()`.
 pub struct Config {
     /// String representation of a port number.
     /// 
     /// It should be converted to a numerical value
     /// before starting the main loop.
     pub port: u16,
+
+    /// Address to listen on.
+    ///
+    /// `::` listens on all IPv6 and IPv4 addresses,
+    /// `0.0.0.0` on all IPv4 addresses only.
+    pub listen: IpAddr,
     
     /// Path the directory with .png images
     /// 
@@ -39,22 +54,146 @@ pub struct Config {
     /// If false, the first image in alphabetical order is picked
     /// False is recommended only with `delete_images=true`
     pub pick_randomly: bool,
-    pub contacts: BTreeMap<String, String>,
+
+    /// If true, plain text messages are encrypted with the session key.
+    /// If false, they are sent as raw UTF-8, readable by anyone on the path.
+    /// Recieved messages are displayed in either case,
+    /// raw ones marked as unencrypted.
+    pub encrypt_plain: bool,
+
+    /// Cipher suites we agree to use, most preferred first.
+    /// When accepting a connection, the first suite from this list
+    /// supported by the peer is chosen.
+    pub cipher_suites: Vec<CipherSuite>,
+
+    /// Handshake used when dialing a contact.
+    /// Incoming connections are accepted with either handshake.
+    pub handshake: HandshakeMode,
+
+    /// Session keys are updated after this many messages
+    /// have been sent or recieved with them, 0 to disable
+    pub rekey_after_messages: u64,
+
+    /// Session keys are updated after they have been used
+    /// for this many minutes, 0 to disable
+    pub rekey_after_minutes: u64,
+
+    /// The peer is considered offline if nothing has been heard
+    /// from it for this many seconds, 0 to disable.
+    /// Values below twice the heartbeat interval are raised to it
+    pub heartbeat_timeout_seconds: u64,
+
+    /// Kind of identity key generated on the first start.
+    /// Changing it does not affect an existing key.
+    pub identity_key: KeyType,
+    pub contacts: BTreeMap<String, Contact>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "ContactRepr", into = "ContactRepr")]
+/// A single entry of the contact list
+pub struct Contact {
+    /// Address in `ip:port` format
+    pub addr: String,
+
+    /// Secret agreed with the contact in person.
+    ///
+    /// If set, it is mixed into the session key, so that the session
+    /// stays private even if the public key exchange is compromised.
+    /// Both sides must use the same value.
+    pub psk: Option<String>,
+
+    /// Fingerprint of the contact's identity key.
+    ///
+    /// If set, sessions with a peer presenting another key are refused.
+    /// Filled in when the contact is imported from a contact card.
+    pub fingerprint: Option<String>,
+}
+
+/// Contacts are written either as a plain address (`Lena="192.168.0.12:1337"`)
+/// or as a table (`Lena={addr="192.168.0.12:1337", psk="...", fingerprint="SHA256:..."}`)
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ContactRepr {
+    Addr(String),
+    Full {
+        addr: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        psk: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+    },
+}
+
+impl From<ContactRepr> for Contact {
+    fn from(repr: ContactRepr) -> Self {
+        match repr {
+            ContactRepr::Addr(addr) => Contact::new(addr),
+            ContactRepr::Full { addr, psk, fingerprint } => Contact { addr, psk, fingerprint },
+        }
+    }
+}
+
+impl From<Contact> for ContactRepr {
+    fn from(contact: Contact) -> Self {
+        match contact {
+            Contact { addr, psk: None, fingerprint: None } => ContactRepr::Addr(addr),
+            Contact { addr, psk, fingerprint } => ContactRepr::Full { addr, psk, fingerprint },
+        }
+    }
+}
+
+impl Contact {
+    pub fn new(addr: String) -> Self {
+        Contact { addr, psk: None, fingerprint: None }
+    }
+}
+
+impl fmt::Display for Contact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if self.psk.is_some() {
+            write!(f, " (pre-shared key)")?;
+        }
+        if let Some(fingerprint) = &self.fingerprint {
+            write!(f, " [{fingerprint}]")?;
+        }
+        Ok(())
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             port: 1337,
+            listen: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
             assets: "~/.simi/assets".to_owned(),
             delete_images: false,
             pick_randomly: true,
+            encrypt_plain: true,
+            cipher_suites: vec![
+                CipherSuite::Aes256Gcm,
+                CipherSuite::ChaCha20Poly1305,
+                CipherSuite::Aes128Gcm,
+            ],
+            handshake: HandshakeMode::default(),
+            rekey_after_messages: 1000,
+            rekey_after_minutes: 60,
+            heartbeat_timeout_seconds: 30,
+            identity_key: KeyType::default(),
             contacts: BTreeMap::new(),
         }
     }
 }
 
 impl Config {
+    /// Finds a contact with the given address
+    pub fn contact_by_addr(&self, addr: &SocketAddr) -> Option<&Contact> {
+        self.contacts
+            .values()
+            .find(|contact| contact.addr.parse::<SocketAddr>().is_ok_and(|known| same_addr(&known, addr)))
+    }
+
     /// Loads file specified by `PATH_TO_CONFIG` constant and deserializes it
     /// into a new `Config` struct
     /// 
@@ -84,7 +223,20 @@ impl Config {
             Some(val) => val,
             None => return Err("Cannot locate home directory. Why?...".to_owned())
         };
-        fs::write(path, &raw_config).map_err(|e| e.to_string())
+        // Pre-shared keys of contacts are stored here, so the file is
+        // readable only by the current user; writing a temporary file
+        // first also applies that to a config created with wider permissions
+        let tmp_path = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(|e| e.to_string())?;
+        file.write_all(raw_config.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
+        fs::rename(tmp_path, path).map_err(|e| e.to_string())
     }
 }
 
@@ -1,37 +1,63 @@
-use std::net::{TcpListener, TcpStream, SocketAddr};
-use std::os::unix::prelude::{AsFd, AsRawFd};
-use std::io::stdin;
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use bincode::Options;
-use nix::libc::STDIN_FILENO;
-use nix::poll::{PollFd, PollFlags, poll};
-use nix::errno::Errno;
-use rsa::RsaPrivateKey;
-use rand::thread_rng;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use socket2::{Domain, Protocol, Socket};
+use tokio::net::TcpListener;
+use tokio::task::{self, JoinError, JoinHandle, JoinSet};
+use tokio::time::sleep;
+use zeroize::Zeroizing;
 
 
-use crate::config::{Config, canonicalize_home};
+use crate::card::{self, Card};
+use crate::config::{Config, Contact, canonicalize_home};
 use crate::cli::{menu, dialogue, Command};
 use crate::error::{Error, ErrCode, convert_err};
-use crate::proto::message::{Type, Message};
+use crate::keystore::{self, Identity};
+use crate::proto::cipher::CipherSuite;
+use crate::proto::message::{Capabilities, Type, Message};
 use crate::proto::{
-    handshake_init, decline, recieve,
-    accept_or_decline, send, 
-    send_secret, decrypt_secret
+    handshake_init, decline, accept_or_decline,
+    seal_secret, decrypt_secret, secret_id, acknowledgement, open_acknowledgement
 };
-use crate::proto::CryptoContext;
+use crate::proto::{CryptoContext, Handshake, HandshakeMode, fingerprint};
+use crate::proto::pulse::Pulse;
+use crate::proto::state::{self, Action, Notice, State, Verdict};
+use super::backoff::Backoff;
+use super::incoming::{Arrived, Incoming};
+use super::input::Input;
+use super::session::Session;
 use super::{
-    prompt, empty_prompt, named_prompt, 
-    debug_prompt, secret_prompt, toggle_debug
+    prompt, empty_prompt, named_prompt, plain_prompt,
+    debug_prompt, secret_prompt, toggle_debug,
 };
 
+/// Time given to a handshake to complete each of its steps
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Heartbeats are sent this often in sessions with peers supporting them
+#[cfg(not(test))]
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Short enough for tests to outlast the heartbeat timeout
+#[cfg(test)]
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct Application {
     cfg: Config,
     addr: SocketAddr,
     listener: TcpListener,
-    watches: [PollFd; 2],
-    private_key: RsaPrivateKey,
+    input: Input,
+    identity: Identity,
+    /// Secrets sent in the current session and not acknowledged yet
+    unacked: HashSet<u64>,
+    /// Secret whose text is the next line typed in the session
+    pending_secret: Option<PendingSecret>,
+    /// Connections waiting for their first message
+    incoming: Incoming,
 }
 
 #[derive(PartialEq)]
@@ -40,145 +66,501 @@ enum CloseCaused {
     Locally,
 }
 
+/// Something the event loops have to react to
+enum Event {
+    /// A line typed by the user
+    Line(Zeroizing<String>),
+    /// Stdin has been closed
+    EndOfInput,
+    /// A connection has sent its first message
+    Arrived(Arrived),
+}
+
+/// A secret command waiting for the text of the secret
+struct PendingSecret {
+    /// Image, or directory of images, to hide the secret in
+    path: Option<String>,
+    sign: bool,
+}
+
+/// What has been taken from a message of the peer
+#[derive(Default)]
+struct Opened {
+    /// Text to display, if any
+    text: Option<Zeroizing<String>>,
+    /// Whether the text is a secret with a valid signature
+    signed: bool,
+    /// Identifier of a secret, for the acknowledgement
+    secret: u64,
+    /// Our reply to a key update
+    rekey_reply: Option<Vec<u8>>,
+}
+
+/// Heartbeats of a session
+struct Liveness {
+    /// Whether the peer supports heartbeats
+    beating: bool,
+    /// Time of silence after which the peer is considered offline, if any
+    peer_timeout: Option<Duration>,
+    /// When the peer has last been heard from
+    heard: Instant,
+    /// When we have last sent a heartbeat
+    beaten: Instant,
+    /// Authentication of heartbeats in both directions
+    pulse: Pulse,
+}
+
+impl Liveness {
+    fn new(beating: bool, peer_timeout: Option<Duration>, pulse: Pulse) -> Self {
+        Self { beating, peer_timeout, heard: Instant::now(), beaten: Instant::now(), pulse }
+    }
+
+    /// Sends a heartbeat if one is due
+    async fn beat(&mut self, session: &mut Session, port: u16) -> Result<(), Error> {
+        if self.beating && self.beaten.elapsed() >= HEARTBEAT_INTERVAL {
+            session.send(Message::new_heartbeat(port, self.pulse.beat())).await?;
+            self.beaten = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Only authenticated messages count, anyone on the path
+    /// could send the others to keep a dead session looking alive
+    fn heard(&mut self) {
+        self.heard = Instant::now();
+    }
+
+    /// Checks a heartbeat of the peer
+    fn felt(&mut self, heartbeat: &Message) {
+        match self.pulse.check(heartbeat.data.as_deref().unwrap_or_default()) {
+            Ok(()) => self.heard(),
+            Err(e) => debug_prompt(&format!("heartbeat rejected: {}", e.descr)),
+        }
+    }
+
+    /// Whether nothing has been heard from the peer for too long
+    fn gone(&self) -> bool {
+        self.peer_timeout.is_some_and(|timeout| self.heard.elapsed() >= timeout)
+    }
+
+    /// Time left until a heartbeat is due or the peer times out
+    fn wake_up(&self) -> Option<Duration> {
+        [
+            self.beating.then(|| HEARTBEAT_INTERVAL.saturating_sub(self.beaten.elapsed())),
+            self.peer_timeout.map(|timeout| timeout.saturating_sub(self.heard.elapsed())),
+        ].into_iter().flatten().min()
+    }
+}
+
+/// Parameters of handshakes with the peer we are waiting for.
+///
+/// Handshakes run in the background, so the parameters are owned.
+struct Peer {
+    addr: SocketAddr,
+    /// Fingerprint of our own identity key
+    fingerprint: String,
+    mode: HandshakeMode,
+    port: u16,
+    private_key: RsaPrivateKey,
+    suites: Vec<CipherSuite>,
+    psk: Option<Zeroizing<Vec<u8>>>,
+    pinned: Option<String>,
+}
+
+impl Peer {
+    fn handshake<'a>(&'a self, abandoned: &'a AtomicBool) -> Handshake<'a> {
+        Handshake {
+            mode: self.mode,
+            port: self.port,
+            private_key: &self.private_key,
+            suites: &self.suites,
+            psk: self.psk.as_deref().map(Vec::as_slice),
+            pinned: self.pinned.as_deref(),
+            abandoned: Some(abandoned),
+        }
+    }
+}
+
+/// Handshakes run in the background while waiting for the peer.
+///
+/// Dropping their tasks does not stop them, so once the attempt is dropped
+/// they are told that the session is no longer wanted.
+#[derive(Default)]
+struct Attempt(Arc<AtomicBool>);
+
+impl Attempt {
+    fn abandoned(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.0)
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What the user has been told of our failed dials
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Reported {
+    Nothing,
+    /// The peer has declined our dial to keep the connection they have dialed
+    TieBreak,
+    /// The peer is offline or cannot be reached, which is told only once
+    Offline,
+}
+
+/// Connections to the peer we are waiting for: our dial and the incoming
+/// ones, of which only one carries the session
+struct Waiting {
+    peer: Arc<Peer>,
+    backoff: Backoff,
+    attempt: Attempt,
+    dialing: Option<JoinHandle<Result<Option<Established>, Error>>>,
+    /// When to dial again after a failed attempt
+    redial_at: Option<Instant>,
+    reported: Reported,
+    /// Handshakes with incoming connections in progress
+    handshakes: JoinSet<Result<Option<Established>, Error>>,
+    /// A connection set aside while the one in the other direction
+    /// is in progress, and whether we have dialed it
+    fallback: Option<(Established, bool)>,
+}
+
+impl Waiting {
+    /// Starts dialing `peer`
+    fn new(peer: Peer) -> Self {
+        let peer = Arc::new(peer);
+        let attempt = Attempt::default();
+        let dialing = Some(dial_in_background(&peer, &attempt));
+        Self {
+            peer,
+            backoff: Backoff::default(),
+            attempt,
+            dialing,
+            redial_at: None,
+            reported: Reported::Nothing,
+            handshakes: JoinSet::new(),
+            fallback: None,
+        }
+    }
+
+    /// Answers a connection whose first message has arrived
+    fn answer(&mut self, arrived: Arrived) {
+        let peer = Arc::clone(&self.peer);
+        let abandoned = self.attempt.abandoned();
+        self.handshakes.spawn_blocking(move || answer_peer(&peer, arrived, &abandoned));
+    }
+
+    /// Takes the outcome of our dial, which is made again later if it has failed
+    fn dialed(&mut self, outcome: Result<Option<Established>, Error>) -> Option<(Established, bool)> {
+        self.dialing = None;
+        let offline = self.reported == Reported::Offline;
+        match outcome {
+            Ok(Some(established)) => return Some((established, true)),
+            // The peer has dialed us too and declines ours to keep their connection;
+            // it is dialed again only in case theirs fails
+            Ok(None) if !self.handshakes.is_empty() || self.fallback.is_some() => {
+                debug_prompt("both sides have dialed, the peer keeps the other connection");
+                if !offline {
+                    self.reported = Reported::TieBreak;
+                }
+            }
+            Err(e) if !offline => {
+                prompt(&format!("cannot reach your peer: {}. Wait until they connect or leave", e.descr));
+                self.reported = Reported::Offline;
+            }
+            _ if !offline => {
+                prompt("your peer is offline. Wait until they connect or leave");
+                self.reported = Reported::Offline;
+            }
+            Err(e) => debug_prompt(&format!("cannot reach your peer: {}", e.descr)),
+            _ => (),
+        }
+        let delay = self.backoff.next_delay();
+        debug_prompt(&format!("dialing again in {} ms", delay.as_millis()));
+        self.redial_at = Some(Instant::now() + delay);
+        None
+    }
+
+    fn redial(&mut self) {
+        self.redial_at = None;
+        self.dialing = Some(dial_in_background(&self.peer, &self.attempt));
+    }
+
+    /// Decides on a connection that has completed its handshake, if any,
+    /// and on the one set aside.
+    ///
+    /// Returns the connection to carry the session; the others are abandoned.
+    fn choose(&mut self, established: Option<(Established, bool)>) -> Option<Established> {
+        let (established, dialed) = match established {
+            Some(val) => val,
+            // The connection in the other direction has failed,
+            // so the one set aside is used
+            None if self.fallback.as_ref().is_some_and(|(_, dialed)|
+                if *dialed { self.handshakes.is_empty() } else { self.dialing.is_none() }) => self.fallback.take().unwrap(),
+            None => return None,
+        };
+        // If both peers have dialed each other, both must keep the same connection
+        let other_in_progress = if dialed { !self.handshakes.is_empty() } else { self.dialing.is_some() };
+        if other_in_progress && dialed != keeps_dialed(&self.peer.fingerprint, &fingerprint(&established.1.peer_public_key)) {
+            debug_prompt("both sides have dialed, waiting for the other connection");
+            self.fallback = Some((established, dialed));
+            return None;
+        }
+        self.dialing = None;
+        self.fallback = None;
+        self.handshakes = JoinSet::new();
+        self.attempt = Attempt::default();
+        Some(established)
+    }
+
+    /// Dials the peer again once a session with them is over
+    fn resume(&mut self) {
+        self.backoff.reset();
+        self.redial_at = Some(Instant::now() + self.backoff.next_delay());
+    }
+}
+
+/// A connection that has completed the handshake, with the session keys
+type Established = (std::net::TcpStream, CryptoContext);
+
 impl Application {
-    pub fn initialize(cfg: Config) -> Result<Self, Error> {
-        let addr = SocketAddr::from(([0, 0, 0, 0], cfg.port));
-        let listener = TcpListener::bind(addr)
+    pub fn initialize(cfg: Config, identity: Identity, input: Input) -> Result<Self, Error> {
+        let addr = SocketAddr::new(cfg.listen, cfg.port);
+        let listener = listen(addr)
+            .or_else(|e| match addr {
+                // IPv6 may be disabled on this host
+                SocketAddr::V6(v6) if v6.ip().is_unspecified() =>
+                    listen(SocketAddr::from((Ipv4Addr::UNSPECIFIED, cfg.port))),
+                _ => Err(e),
+            })
             .map_err(|e| convert_err(e, ErrCode::Fatal))?;
-        let listener_fd = listener.as_fd().as_raw_fd();
-        let watches = [
-            PollFd::new(STDIN_FILENO, PollFlags::POLLIN),
-            PollFd::new(listener_fd, PollFlags::POLLIN)];
-        
-        let bits = 2048;
-        let mut rng = thread_rng();
-        let private_key = RsaPrivateKey::new(&mut rng, bits).unwrap();
+        let addr = listener.local_addr().map_err(|e| convert_err(e, ErrCode::Fatal))?;
 
-        Ok(Self { cfg, addr, listener, watches, private_key})
+        Ok(Self {
+            cfg,
+            addr,
+            listener,
+            input,
+            identity,
+            unacked: HashSet::new(),
+            pending_secret: None,
+            incoming: Incoming::default(),
+        })
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        let mut buffer = String::new();
-        prompt(&format!("listening on port {}", self.cfg.port));
+    pub async fn run(&mut self) -> Result<(), Error> {
+        prompt(&format!("listening on {}", self.addr));
         loop {
-            match poll(&mut self.watches, -1) {
-                Ok(0) | Err(Errno::EAGAIN) | Err(Errno::EINTR) => continue,
-                Ok(val) => val,
-                Err(e) => return Err(Error::new(ErrCode::Fatal, e.to_string())),
-            };
-            if self.watches[0].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
-                // Events in stdio
-                // read line, interpret, execute
-                stdin().read_line(&mut buffer).unwrap();
-                match menu::interpret(buffer.trim()) {
+            match self.next_event().await? {
+                // interpret, execute
+                Event::Line(line) => match menu::interpret(line.trim()) {
                     Err(e) => prompt(&e.descr),
                     Ok(Command::Exit) => break,
-                    Ok(cmd) => self.menu_execute(cmd),
-                }
-                buffer.clear();
-            }
-            if self.watches[1].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
+                    Ok(cmd) => self.menu_execute(cmd).await,
+                },
+                Event::EndOfInput => break,
                 // TCP connection recieved, decline it
-                let connection = self.listener.accept()
-                    .map_err(|e| Error::new(ErrCode::Fatal, e.to_string()))?;
-                decline(connection.0, self.cfg.port);
+                Event::Arrived(arrived) => decline_in_background(arrived, self.cfg.port),
             }
         }
         Ok(())
     }
 
-    fn waiting_loop(&mut self, desired_addr: SocketAddr, name: &str) -> Result<(), Error> {
-        // First try connecting to the remote peer
-        debug_prompt("dialing...");
-        let ten_sec = Duration::from_secs(10);
-        let mut stream = TcpStream::connect_timeout(&desired_addr, ten_sec)
-            .map_err(|e| Error::new(ErrCode::Network, e.to_string()))?;
-        stream.set_write_timeout(Some(ten_sec)).unwrap();
-        stream.set_read_timeout(Some(ten_sec)).unwrap();
-        if let Some(ctx) = handshake_init(&mut stream, self.cfg.port, &self.private_key)? {
-            // On success - wait until this connection is closed
-            let cause = self.connected_loop(desired_addr, name, ctx)?;
+    async fn waiting_loop(&mut self, desired_addr: SocketAddr, name: &str) -> Result<(), Error> {
+        // The peer is dialed until they answer,
+        // the user and incoming connections are served meanwhile
+        let mut waiting = Waiting::new(self.peer(desired_addr));
+        while let Some((stream, ctx)) = self.wait_for_peer(&mut waiting).await? {
+            let cause = self.connected_loop(stream, name, ctx).await?;
             if cause == CloseCaused::Locally {
                 // if it was closed by the local user - return to the idle loop,
-                // otherwise go to the wait loop
+                // otherwise keep waiting
                 return Ok(());
             }
-        } else {
-            prompt("your peer is offline. Wait until they connect or leave");
+            // The user has been told the peer is gone, dial them until they are back
+            waiting.resume();
         }
-        let mut buffer = String::new();
+        Ok(())
+    }
+
+    /// Serves the user and the connections to the peer until one of them
+    /// completes its handshake and is chosen to carry the session.
+    ///
+    /// Returns `None` if the user has left.
+    async fn wait_for_peer(&mut self, waiting: &mut Waiting) -> Result<Option<Established>, Error> {
         loop {
-            match poll(&mut self.watches, -1) {
-                Ok(0) | Err(Errno::EAGAIN) | Err(Errno::EINTR) => continue,
-                Ok(val) => val,
-                Err(e) => return Err(Error::new(ErrCode::Fatal, e.to_string())),
-            };
-            if self.watches[0].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
-                // Events in stdio
-                // read line, interpret, execute
-                stdin().read_line(&mut buffer).unwrap();
-                match dialogue::interpret(buffer.trim()) {
-                    Err(e) => prompt(&e.descr),
-                    Ok(Command::Exit) => break,
-                    Ok(cmd) => self.waiting_execute(cmd),
-                }
-                buffer.clear();
-            }
-            if self.watches[1].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
-                // TCP connection recieved, decide on it
-                let connection = self.listener.accept()
-                    .map_err(|e| convert_err(e, ErrCode::Fatal))?;
-                match accept_or_decline(&self.private_key, connection, self.cfg.port, &desired_addr) {
-                    Ok(Some(ctx)) => {
-                        let cause = self.connected_loop(desired_addr, name, ctx)?;
-                        if cause == CloseCaused::Locally {
-                            return Ok(());
+            let dialing = &mut waiting.dialing;
+            let handshakes = &mut waiting.handshakes;
+            let redial_in = waiting.redial_at.map(|at| at.saturating_duration_since(Instant::now()));
+            let established = tokio::select! {
+                event = self.next_event() => match event? {
+                    // interpret, execute
+                    Event::Line(line) => {
+                        match dialogue::interpret(line.trim()) {
+                            Err(e) => prompt(&e.descr),
+                            Ok(Command::Exit) => return Ok(None),
+                            Ok(cmd) => self.waiting_execute(cmd),
                         }
+                        None
+                    }
+                    Event::EndOfInput => return Ok(None),
+                    // TCP connection recieved, decide on it
+                    Event::Arrived(arrived) => {
+                        waiting.answer(arrived);
+                        None
                     }
-                    Ok(None) | Err(_) => continue,
-                    // Err(e) => return Err(e),
+                },
+                dialed = async { dialing.as_mut().unwrap().await }, if dialing.is_some() => waiting.dialed(joined(dialed)),
+                () = sleep_for(redial_in) => {
+                    waiting.redial();
+                    None
                 }
+                Some(answered) = handshakes.join_next() => match joined(answered) {
+                    Ok(established) => established.map(|established| (established, false)),
+                    Err(e) => {
+                        prompt(&format!("incoming connection failed: {}", e.descr));
+                        None
+                    }
+                },
+            };
+            if let Some(established) = waiting.choose(established) {
+                return Ok(Some(established));
             }
         }
-        Ok(())
     }
 
-    fn connected_loop(&mut self, address: SocketAddr, name: &str, ctx: CryptoContext) -> Result<CloseCaused, Error> {
+    /// Runs the session over `stream`, the connection the handshake
+    /// was made on, until either side closes it
+    async fn connected_loop(&mut self, stream: std::net::TcpStream, name: &str, mut ctx: CryptoContext) -> Result<CloseCaused, Error> {
         prompt("connected to the peer");
-        let mut buffer = String::new();
+        self.unacked.clear();
+        self.pending_secret = None;
+        let mut session = match Session::new(stream) {
+            Ok(val) => val,
+            Err(e) => return Ok(connection_lost(&e)),
+        };
+        let mut liveness = Liveness::new(
+            ctx.capabilities().contains(Capabilities::HEARTBEAT),
+            self.heartbeat_timeout(&ctx),
+            ctx.pulse());
+        // Messages recieved while the session keys were busy with a secret
+        let mut deferred = VecDeque::new();
         loop {
-            match poll(&mut self.watches, -1) {
-                Ok(0) | Err(Errno::EAGAIN) | Err(Errno::EINTR) => continue,
-                Ok(val) => val,
-                Err(e) => return Err(Error::new(ErrCode::Fatal, e.to_string())),
-            };
-            if self.watches[0].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
-                stdin().read_line(&mut buffer).unwrap();
-                match dialogue::interpret(buffer.trim()) {
-                    Err(e) => {
-                        prompt(&e.descr);
-                    }
-                    Ok(Command::Exit) => {
-                        let mut stream = TcpStream::connect(address)
-                            .map_err(|e| convert_err(e, ErrCode::Network))?;
-                        send(&mut stream, Message::new_close(self.cfg.port, ctx.nonce))?;
-                        return Ok(CloseCaused::Locally)
-                    }
-                    Ok(cmd) => self.dialogue_execute(cmd, &address, &ctx)?
+            if ctx.rekey_due(self.cfg.rekey_after_messages, self.rekey_interval()) {
+                debug_prompt("updating session keys");
+                if let Err(e) = self.rekey(&mut session, &mut ctx).await {
+                    return Ok(connection_lost(&e));
                 }
-                buffer.clear();
             }
-            if self.watches[1].revents().unwrap_or(PollFlags::empty()).contains(PollFlags::POLLIN) {
-                let connection = self.listener.accept()
-                    .map_err(|e| convert_err(e, ErrCode::Fatal))?;
-                    if let Ok(true) = self.handle_incoming_connection(connection, &address, name, &ctx) {
-                        return Ok(CloseCaused::ByRemote);
+            if let Err(e) = liveness.beat(&mut session, self.cfg.port).await {
+                return Ok(connection_lost(&e));
+            }
+            let wake_up = [self.rekey_timeout(&ctx), liveness.wake_up()].into_iter().flatten().min();
+            let mut msg = if let Some(msg) = deferred.pop_front() {
+                msg
+            } else {
+                tokio::select! {
+                    // Messages that have arrived are handled before the timeout is checked
+                    biased;
+                    msg = session.recieve() => match msg {
+                        // Heartbeats only keep the session alive
+                        Some(Ok(msg)) if msg.t == Type::Heartbeat => {
+                            liveness.felt(&msg);
+                            continue;
+                        }
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => return Ok(connection_lost(&e)),
+                        None => {
+                            prompt("your peer disconnected. Wait for them or leave");
+                            return Ok(CloseCaused::ByRemote);
+                        }
+                    },
+                    event = self.next_event() => match event? {
+                        Event::Line(line) => {
+                            let executed = match self.pending_secret.take() {
+                                Some(pending) => {
+                                    let job = self.seal_job(&pending, line);
+                                    let (keys, sealed) = match with_keys(ctx, job, &mut session, &mut liveness, &mut deferred, self.cfg.port).await {
+                                        Ok(val) => val,
+                                        Err(cause) => return Ok(cause),
+                                    };
+                                    ctx = keys;
+                                    self.send_secret(sealed, &mut session, &ctx).await
+                                }
+                                None => match dialogue::interpret(line.trim()) {
+                                    Err(e) => {
+                                        prompt(&e.descr);
+                                        Ok(())
+                                    }
+                                    Ok(Command::Exit) => return Ok(self.close(session, &mut ctx).await),
+                                    Ok(cmd) => self.dialogue_execute(cmd, &mut session, &mut ctx).await,
+                                },
+                            };
+                            if let Err(e) = executed {
+                                return Ok(connection_lost(&e));
+                            }
+                            continue;
+                        }
+                        Event::EndOfInput => return Ok(self.close(session, &mut ctx).await),
+                        // We are busy, decline any other connection
+                        Event::Arrived(arrived) => {
+                            decline_in_background(arrived, self.cfg.port);
+                            continue;
+                        }
+                    },
+                    () = sleep_for(wake_up) => {
+                        if liveness.gone() {
+                            return Ok(went_offline(session.peer()));
+                        }
+                        continue;
                     }
+                }
+            };
+            debug_prompt(&format!("I recieved [{msg:?}]"));
+            let (verdict, opened) = if msg.t == Type::Speak {
+                let image = msg.data.take().unwrap_or_default();
+                let (keys, opened) = match open_secret(image, ctx, &mut session, &mut liveness, &mut deferred, self.cfg.port).await {
+                    Ok(val) => val,
+                    Err(cause) => return Ok(cause),
+                };
+                ctx = keys;
+                opened
+            } else {
+                self.open_message(&mut msg, &mut ctx, &mut liveness)
+            };
+            match self.follow(msg.t, verdict, opened, &mut session, name, &mut ctx).await {
+                Ok(true) => return Ok(CloseCaused::ByRemote),
+                Ok(false) => (),
+                Err(e) => return Ok(connection_lost(&e)),
             }
         }
     }
 
-    fn menu_execute(&mut self, cmd: Command) {
+    /// Waits for a line from the user or a connection whose first message
+    /// has arrived. New connections are accepted meanwhile.
+    async fn next_event(&mut self) -> Result<Event, Error> {
+        loop {
+            tokio::select! {
+                line = self.input.line() => return Ok(line.map_or(Event::EndOfInput, Event::Line)),
+                arrived = self.incoming.next() => return Ok(Event::Arrived(arrived)),
+                accepted = self.listener.accept() => {
+                    let (stream, addr) = accepted.map_err(|e| convert_err(e, ErrCode::Fatal))?;
+                    self.incoming.admit(stream, addr);
+                }
+            }
+        }
+    }
+
+    /// Tells the peer that we are leaving and closes the session
+    async fn close(&self, session: Session, ctx: &mut CryptoContext) -> CloseCaused {
+        let sealed = ctx.seal(Type::Close, &[]);
+        session.close(Message::new_close(self.cfg.port, sealed)).await;
+        CloseCaused::Locally
+    }
+
+    async fn menu_execute(&mut self, cmd: Command) {
         match cmd {
             Command::List => {
                 if self.cfg.contacts.is_empty() {
@@ -191,148 +573,852 @@ impl Application {
                 }
             }
             Command::Add(alias, addr) => {
-                self.cfg.contacts.insert(alias, addr);
+                self.cfg.contacts.insert(alias, Contact::new(addr));
                 empty_prompt()
             }
             Command::Remove(alias) => {
-                if let None = self.cfg.contacts.remove(&alias) {
-                    prompt(&format!("alias {} not found", alias))
+                if self.cfg.contacts.remove(&alias).is_none() {
+                    prompt(&format!("alias {alias} not found"));
                 } else {
-                    empty_prompt()
+                    empty_prompt();
                 }
             }
             Command::Save => {
                 if let Err(e) = self.cfg.save() {
-                    prompt(&format!("cannot save config: {}", e))
+                    prompt(&format!("cannot save config: {e}"));
                 } else {
-                    empty_prompt()
+                    empty_prompt();
                 }
             }
             Command::DialIp(ip) => {
                 let addr = ip.parse::<SocketAddr>().unwrap();
-                self.dial(addr, &ip)
+                self.dial(addr, &ip).await;
             }
             Command::DialAlias(alias) => {
                 let ip = match self.cfg.contacts.get(&alias) {
-                    Some(val) => val,
+                    Some(val) => &val.addr,
                     None => {
-                        prompt(&format!("alias {} not found", alias));
+                        prompt(&format!("alias {alias} not found"));
                         return;
                     }
                 };
                 let addr = ip.parse::<SocketAddr>().unwrap();
-                self.dial(addr, &alias)
+                self.dial(addr, &alias).await;
             }
             Command::Debug => {
                 toggle_debug();
                 empty_prompt();
             }
+            Command::Passwd => self.change_passphrase().await,
+            Command::Export(addr) => self.export_card(addr.as_deref()),
+            Command::Import(alias) => self.import_card(alias).await,
             _ => {}
         }
     }
 
-    fn dialogue_execute(
+    async fn dialogue_execute(
         &mut self,
         cmd: Command,
-        addr: &SocketAddr,
-        ctx: &CryptoContext
+        session: &mut Session,
+        ctx: &mut CryptoContext
     ) -> Result<(), Error> {
         match cmd {
             Command::SpeakPlain(text) => {
-                let mut stream = TcpStream::connect_timeout(addr, Duration::from_secs(10))
-                    .map_err(|e| convert_err(e, ErrCode::Network))?;
-                send(&mut stream, Message::new_speak_plain(self.cfg.port, text.into_bytes()))?;
+                let msg = if self.cfg.encrypt_plain {
+                    Message::new_speak_sealed(self.cfg.port, ctx.seal(Type::SpeakSealed, text.as_bytes()))
+                } else {
+                    Message::new_speak_plain(self.cfg.port, text.into_bytes())
+                };
+                match session.send(msg).await {
+                    Ok(()) => empty_prompt(),
+                    Err(e) if e.code() == ErrCode::Network => return Err(e),
+                    Err(e) => prompt(&e.descr),
+                }
+            }
+            Command::Rekey if !ctx.capabilities().contains(Capabilities::REKEY) =>
+                prompt("your peer does not support key updates"),
+            Command::Rekey => {
+                self.rekey(session, ctx).await?;
                 empty_prompt();
             }
-            Command::Secret(s) => {
-                let mut buf = String::new();
+            Command::Secret(_, true) if !ctx.capabilities().contains(Capabilities::SIGNED_SECRETS) =>
+                prompt("your peer cannot verify signed secrets, send it without --sign"),
+            Command::Secret(path, sign) => {
+                // The text is the next line, so that the session goes on while it is typed
                 prompt("enter secret message:");
-                stdin().read_line(&mut buf).unwrap();
-                let mut stream = TcpStream::connect_timeout(addr, Duration::from_secs(10))
-                    .map_err(|e| convert_err(e, ErrCode::Network))?;
+                self.pending_secret = Some(PendingSecret { path, sign });
+            }
+            _ => {}
+        }
+        Ok(())
+    }
 
-                let path = if let Some(path) = s {
-                    canonicalize_home(&path).unwrap()
-                } else {
-                    canonicalize_home(&self.cfg.assets).unwrap()
-                };
-                if let Err(e) = send_secret(&mut stream, self.cfg.port, &buf, path, &ctx.session_key) {
-                    prompt(&e.descr);
-                } else {
-                    empty_prompt();
+    /// Hides `text` in an image to be sent to the peer.
+    ///
+    /// Images are large and signatures slow, so this is run
+    /// off the runtime by `with_keys`.
+    fn seal_job(
+        &mut self,
+        pending: &PendingSecret,
+        text: Zeroizing<String>
+    ) -> impl FnOnce(&mut CryptoContext) -> Result<(u64, Message), Error> + Send + 'static {
+        let port = self.cfg.port;
+        let path = canonicalize_home(pending.path.as_deref().unwrap_or(&self.cfg.assets)).unwrap();
+        let signer = if pending.sign { self.identity.key().cloned() } else { None };
+        move |ctx| seal_secret(port, &text, path, signer.as_ref(), ctx)
+    }
+
+    /// Sends a secret that has been hidden in an image to the peer
+    async fn send_secret(
+        &mut self,
+        sealed: Result<(u64, Message), Error>,
+        session: &mut Session,
+        ctx: &CryptoContext
+    ) -> Result<(), Error> {
+        let sent = match sealed {
+            Ok((id, msg)) => session.send(msg).await.map(|()| id),
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(id) => {
+                if ctx.capabilities().contains(Capabilities::ACK) {
+                    self.unacked.insert(id);
                 }
+                empty_prompt();
             }
-            _ => {}
+            Err(e) if e.code() == ErrCode::Network => return Err(e),
+            Err(e) => prompt(&e.descr),
         }
         Ok(())
     }
 
     fn waiting_execute(&mut self, cmd: Command) {
         match cmd {
-            Command::SpeakPlain(_) | Command::Secret(_) => 
+            Command::SpeakPlain(_) | Command::Secret(..) | Command::Rekey => 
                 prompt("your peer is disconnected. No messages sent"),
             _ => {}
         }
     }
 
-    fn dial(&mut self, addr: SocketAddr, name: &str) {
-        if let Err(e) = self.waiting_loop(addr, name) {
+    /// Re-encrypts the identity key under a new passphrase.
+    /// The current passphrase is required.
+    ///
+    /// Deriving keys from passphrases is slow on purpose,
+    /// so it is done off the runtime.
+    async fn change_passphrase(&mut self) {
+        let Some(key) = self.identity.get().cloned() else {
+            prompt("identity key is not ready yet");
+            return;
+        };
+        let current = self.input.hidden("enter current passphrase:").await;
+        if let Err(e) = in_background(move || keystore::load(&current)).await {
+            prompt(&e.descr);
+            return;
+        }
+        let Some(passphrase) = keystore::ask_new_passphrase(&mut self.input).await else {
+            prompt("passphrase not changed");
+            return;
+        };
+        match in_background(move || keystore::save(&key, &passphrase)).await {
+            Ok(()) => prompt("passphrase changed"),
+            Err(e) => prompt(&format!("cannot save identity key: {}", e.descr)),
+        }
+    }
+
+    /// Prints our contact card as text and as a QR code.
+    ///
+    /// `addr` is the address other users should dial, either `ip`
+    /// or `ip:port`; if omitted, the address of the default route is used.
+    fn export_card(&mut self, addr: Option<&str>) {
+        let port = self.cfg.port;
+        let resolved = match addr {
+            Some(addr) => addr.parse::<SocketAddr>().ok()
+                .or_else(|| addr.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, port))),
+            None => local_ip().map(|ip| SocketAddr::new(ip, port)),
+        };
+        let Some(addr) = resolved else {
+            match addr {
+                Some(addr) => prompt(&format!("\"{addr}\" is not a valid address")),
+                None => prompt("cannot determine our address, specify it: export <ip>"),
+            }
+            return;
+        };
+        let Some(key) = self.identity.get() else {
+            prompt("identity key is not ready yet");
+            return;
+        };
+        let card = Card::new(addr.to_string(), RsaPublicKey::from(key));
+        match card.armor().and_then(|text| Ok((text, card.qr()?))) {
+            Ok((text, qr)) => {
+                println!("{text}");
+                println!("{qr}");
+                prompt(&format!("address {addr}, fingerprint {}", fingerprint(&card.pkey)));
+            }
+            Err(e) => prompt(&format!("cannot export contact card: {}", e.descr)),
+        }
+    }
+
+    /// Reads a contact card pasted by the user and adds it under `alias`
+    /// with the fingerprint pinned. Like `add`, it is written to disk
+    /// only by `save`. A pre-shared key of an existing contact
+    /// with the same alias is kept.
+    async fn import_card(&mut self, alias: String) {
+        prompt("paste the contact card, then press enter on an empty line:");
+        let mut text = String::new();
+        while let Some(line) = self.input.line().await {
+            if line.trim().is_empty() {
+                break;
+            }
+            text.push_str(&line);
+            text.push('\n');
+            if card::is_footer(&line) {
+                break;
+            }
+        }
+        let card = match Card::from_armor(&text) {
+            Ok(val) => val,
+            Err(e) => {
+                prompt(&e.descr);
+                return;
+            }
+        };
+        if card.addr.parse::<SocketAddr>().is_err() {
+            prompt(&format!("contact card carries invalid address \"{}\"", card.addr));
+            return;
+        }
+        let fingerprint = fingerprint(&card.pkey);
+        prompt(&format!(
+            "contact '{alias}' with address '{}' and fingerprint {fingerprint} has been added (use `save` to write it to disk)",
+            card.addr));
+        let psk = self.cfg.contacts.remove(&alias).and_then(|contact| contact.psk);
+        self.cfg.contacts.insert(alias, Contact { addr: card.addr, psk, fingerprint: Some(fingerprint) });
+    }
+
+    /// Sends a key update request to the peer
+    async fn rekey(&self, session: &mut Session, ctx: &mut CryptoContext) -> Result<(), Error> {
+        let Some(sealed) = ctx.start_rekey() else {
+            prompt("key update already in progress");
+            return Ok(());
+        };
+        let sent = session.send(Message::new_rekey(self.cfg.port, sealed)).await;
+        if sent.is_err() {
+            ctx.cancel_rekey();
+        }
+        sent
+    }
+
+    /// Age of the session keys at which they are updated.
+    /// The value comes from the config, so it must not overflow
+    fn rekey_interval(&self) -> Duration {
+        Duration::from_secs(self.cfg.rekey_after_minutes.saturating_mul(60))
+    }
+
+    /// Time left until the session keys are due for an update,
+    /// `None` if they are never updated on timeout or an update
+    /// is already waiting for the peer's reply
+    fn rekey_timeout(&self, ctx: &CryptoContext) -> Option<Duration> {
+        let interval = self.rekey_interval();
+        if interval.is_zero() || !ctx.capabilities().contains(Capabilities::REKEY) || ctx.rekey_pending() {
+            return None;
+        }
+        Some(interval.saturating_sub(ctx.established.elapsed()))
+    }
+
+    /// Time of silence after which the peer is considered offline,
+    /// `None` if it is never considered so
+    fn heartbeat_timeout(&self, ctx: &CryptoContext) -> Option<Duration> {
+        if self.cfg.heartbeat_timeout_seconds == 0 || !ctx.capabilities().contains(Capabilities::HEARTBEAT) {
+            return None;
+        }
+        Some(Duration::from_secs(self.cfg.heartbeat_timeout_seconds).max(2 * HEARTBEAT_INTERVAL))
+    }
+
+    /// Parameters of handshakes with the peer at `addr`, taking
+    /// its pre-shared key and pinned fingerprint from the contact list.
+    ///
+    /// Must be called only after the identity key is ready.
+    fn peer(&self, addr: SocketAddr) -> Peer {
+        let contact = self.cfg.contact_by_addr(&addr);
+        let private_key = self.identity.key().expect("identity key is not ready");
+        Peer {
+            addr,
+            fingerprint: fingerprint(&RsaPublicKey::from(private_key)),
+            mode: self.cfg.handshake,
+            port: self.cfg.port,
+            private_key: private_key.clone(),
+            suites: self.cfg.cipher_suites.clone(),
+            psk: contact
+                .and_then(|contact| contact.psk.clone())
+                .map(|psk| Zeroizing::new(psk.into_bytes())),
+            pinned: contact.and_then(|contact| contact.fingerprint.clone()),
+        }
+    }
+
+    async fn dial(&mut self, addr: SocketAddr, name: &str) {
+        if let Err(e) = self.identity.wait().await {
+            prompt(&e.descr);
+            return;
+        }
+        if let Err(e) = self.waiting_loop(addr, name).await {
             prompt(&format!("connection was broken because: {}", e.descr));
         }
         prompt("you are in the menu now");
     }
 
-    /// Handle incoming TCP connection when connected to some peer.
-    /// 
-    /// Returns `true` if the peer sends a valid `close` message
-    /// with matching nonce.
-    fn handle_incoming_connection(&self,
-        mut connection: (TcpStream, SocketAddr),
-        address: &SocketAddr,
+    /// Authenticates and decrypts a message from the peer arriving
+    /// on the session connection, other than a secret, which is opened
+    /// off the runtime, or a heartbeat.
+    ///
+    /// Returns the verdict the protocol state machine responds to.
+    fn open_message(&mut self, msg: &mut Message, ctx: &mut CryptoContext, liveness: &mut Liveness) -> (Verdict, Opened) {
+        let data = msg.data.take();
+        let sealed = data.as_deref().unwrap_or_default();
+        let mut opened = Opened::default();
+        // Whether the message has been authenticated under the session keys
+        let mut authentic = false;
+        let verdict = match msg.t {
+            Type::Close => match ctx.open(Type::Close, sealed) {
+                Ok(_) => {
+                    authentic = true;
+                    Verdict::Valid
+                }
+                Err(e) => rejected("close message", &e),
+            },
+            Type::SpeakPlain => {
+                opened.text = Some(Zeroizing::new(match data {
+                    Some(data) => String::from_utf8(data).unwrap_or("<invalid encoding>".to_owned()),
+                    None => "<empty message>".to_owned(),
+                }));
+                Verdict::Valid
+            }
+            Type::SpeakSealed => match ctx.open(Type::SpeakSealed, sealed) {
+                Ok(raw_text) => {
+                    opened.text = Some(Zeroizing::new(std::str::from_utf8(&raw_text)
+                        .unwrap_or("<invalid encoding>").to_owned()));
+                    authentic = true;
+                    Verdict::Valid
+                }
+                Err(e) => rejected("message", &e),
+            },
+            Type::Ack | Type::Nack => match open_acknowledgement(msg.t, sealed, ctx) {
+                Ok(id) => {
+                    authentic = true;
+                    if self.unacked.remove(&id) {
+                        Verdict::Valid
+                    } else {
+                        debug_prompt("acknowledgement of an unknown secret");
+                        Verdict::Invalid
+                    }
+                }
+                Err(e) => rejected("acknowledgement", &e),
+            },
+            Type::Rekey => match ctx.handle_rekey(sealed) {
+                Ok(Some(reply)) => {
+                    authentic = true;
+                    opened.rekey_reply = Some(reply);
+                    Verdict::Reply
+                }
+                Ok(None) => {
+                    authentic = true;
+                    Verdict::Valid
+                }
+                Err(e) => rejected("key update", &e),
+            },
+            _ => Verdict::Valid,
+        };
+        if authentic {
+            liveness.heard();
+        }
+        (verdict, opened)
+    }
+
+    /// Carries out the actions of the protocol state machine
+    /// on a message of type `t` from the peer.
+    ///
+    /// Returns `true` if the session is over.
+    async fn follow(&mut self,
+        t: Type,
+        verdict: Verdict,
+        mut opened: Opened,
+        session: &mut Session,
         name: &str,
-        ctx: &CryptoContext
+        ctx: &mut CryptoContext
     ) -> Result<bool, Error> {
-        let msg = recieve(&mut connection.0)?;
-        debug_prompt(&format!("I recieved [{:?}]", msg));
-        if connection.1.ip() == address.ip() && msg.port == address.port() {
-            match msg.t {
-                // something ugly, pls help
-                Type::Close => {
-                    if let Some(data) = msg.data {
-                        let raw_nonce = bincode::DefaultOptions::new()
-                            .with_little_endian()
-                            .deserialize::<u64>(&data);
-                        if let Ok(nonce) = raw_nonce {
-                            if nonce == ctx.nonce {
-                                prompt("your peer disconnected. Wait for them or leave");
-                                return Ok(true)
-                            }
-                        }
-                    }
+        let peer = session.peer();
+        let text = opened.text.as_deref().map_or("", |text| text.trim());
+        for action in state::transition(&State::Connected(peer), peer, t, verdict) {
+            match action {
+                Action::Display(Notice::Message) if t == Type::SpeakPlain => plain_prompt(name, text),
+                Action::Display(Notice::Message) => named_prompt(name, text),
+                Action::Display(Notice::Secret) => secret_prompt(name, text, opened.signed),
+                Action::Display(Notice::Delivered) => prompt("secret delivered"),
+                Action::Display(Notice::Undelivered) => prompt("your peer failed to decrypt the secret"),
+                Action::Reply(t @ (Type::Ack | Type::Nack)) => if ctx.capabilities().contains(Capabilities::ACK) {
+                    session.send(acknowledgement(self.cfg.port, opened.secret, t == Type::Ack, ctx)).await?;
                 },
-                Type::SpeakPlain => {
-                    if let Some(data) = msg.data {
-                        let text = String::from_utf8(data)
-                            .unwrap_or("<invalid encoding>".to_owned());
-                        named_prompt(name, &text);
-                    } else {
-                        named_prompt(name, "<empty message>");
-                    }
+                Action::Reply(Type::Rekey) => if let Some(reply) = opened.rekey_reply.take() {
+                    session.send(Message::new_rekey(self.cfg.port, reply)).await?;
+                },
+                // The keys are replaced as soon as the update is authenticated
+                Action::UpdateKeys => debug_prompt("session keys updated"),
+                Action::Switch(_) => {
+                    prompt("your peer disconnected. Wait for them or leave");
+                    return Ok(true);
                 }
-                Type::Speak => {
-                    if let Some(data) = msg.data {
-                        if let Ok(text) = decrypt_secret(data, &ctx.session_key) {
-                            secret_prompt(name, text.trim());
-                        }
-                    }
+                // Not used in the connected state
+                Action::Reply(_) | Action::Display(Notice::Offline | Notice::WentOffline) => (),
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Runs blocking `job` with the session keys `ctx` off the runtime.
+///
+/// The session goes on meanwhile: heartbeats are sent, and messages
+/// from the peer are put aside in `deferred`, to be handled in order
+/// once the keys are back. The user is served afterwards.
+///
+/// Returns the keys along with the outcome, or how the session has ended.
+async fn with_keys<T: Send + 'static>(
+    mut ctx: CryptoContext,
+    job: impl FnOnce(&mut CryptoContext) -> T + Send + 'static,
+    session: &mut Session,
+    liveness: &mut Liveness,
+    deferred: &mut VecDeque<Message>,
+    port: u16
+) -> Result<(CryptoContext, T), CloseCaused> {
+    let mut work = task::spawn_blocking(move || {
+        let done = job(&mut ctx);
+        (ctx, done)
+    });
+    loop {
+        if let Err(e) = liveness.beat(session, port).await {
+            return Err(connection_lost(&e));
+        }
+        tokio::select! {
+            done = &mut work => return done.map_err(|e| connection_lost(&convert_err(e, ErrCode::Fatal))),
+            msg = session.recieve() => match msg {
+                // Heartbeats are checked without the keys
+                Some(Ok(msg)) if msg.t == Type::Heartbeat => liveness.felt(&msg),
+                Some(Ok(msg)) => deferred.push_back(msg),
+                Some(Err(e)) => return Err(connection_lost(&e)),
+                None => {
+                    prompt("your peer disconnected. Wait for them or leave");
+                    return Err(CloseCaused::ByRemote);
                 }
-                _ => {},
+            },
+            () = sleep_for(liveness.wake_up()) => if liveness.gone() {
+                return Err(went_offline(session.peer()));
+            },
+        }
+    }
+}
+
+/// Opens a secret sent by the peer off the runtime, as our secrets are
+/// sealed, and returns the keys along with the verdict on the secret
+async fn open_secret(
+    image: Vec<u8>,
+    ctx: CryptoContext,
+    session: &mut Session,
+    liveness: &mut Liveness,
+    deferred: &mut VecDeque<Message>,
+    port: u16
+) -> Result<(CryptoContext, (Verdict, Opened)), CloseCaused> {
+    let job = move |ctx: &mut CryptoContext| (secret_id(&image), decrypt_secret(&image, ctx));
+    let (ctx, (secret, decrypted)) = with_keys(ctx, job, session, liveness, deferred, port).await?;
+    let opened = match decrypted {
+        Ok(decrypted) => {
+            liveness.heard();
+            (Verdict::Valid, Opened {
+                text: Some(decrypted.text),
+                signed: decrypted.signed,
+                secret,
+                rekey_reply: None,
+            })
+        }
+        Err(e) => (rejected("secret", &e), Opened { secret, ..Opened::default() }),
+    };
+    Ok((ctx, opened))
+}
+
+/// Reports that a message from the peer has been rejected
+fn rejected(what: &str, e: &Error) -> Verdict {
+    debug_prompt(&format!("{what} rejected: {}", e.descr));
+    Verdict::Invalid
+}
+
+/// Dials the peer in the background
+fn dial_in_background(peer: &Arc<Peer>, attempt: &Attempt) -> JoinHandle<Result<Option<Established>, Error>> {
+    debug_prompt("dialing...");
+    let peer = Arc::clone(peer);
+    let abandoned = attempt.abandoned();
+    task::spawn_blocking(move || dial_peer(&peer, &abandoned))
+}
+
+/// Whether the connection we have dialed is kept when both peers
+/// have dialed each other.
+///
+/// Both peers keep the connection dialed by the one whose identity key
+/// has the smaller fingerprint, so they never end up on different ones.
+fn keeps_dialed(ours: &str, theirs: &str) -> bool {
+    ours < theirs
+}
+
+/// Dials the peer and performs the handshake as the initiator.
+///
+/// Blocks until the handshake is over, so it is run in the background.
+fn dial_peer(peer: &Peer, abandoned: &AtomicBool) -> Result<Option<Established>, Error> {
+    let mut stream = std::net::TcpStream::connect_timeout(&peer.addr, HANDSHAKE_TIMEOUT)
+        .map_err(|e| Error::new(ErrCode::Network, e.to_string()))?;
+    set_timeouts(&stream)?;
+    Ok(handshake_init(&mut stream, &peer.handshake(abandoned))?.map(|ctx| (stream, ctx)))
+}
+
+/// Answers the first message of an incoming connection,
+/// which is accepted only if it comes from the peer.
+///
+/// Blocks until the handshake is over, so it is run in the background.
+fn answer_peer(peer: &Peer, arrived: Arrived, abandoned: &AtomicBool) -> Result<Option<Established>, Error> {
+    let Arrived { mut connection, message } = arrived;
+    set_timeouts(&connection.0)?;
+    Ok(accept_or_decline(&peer.handshake(abandoned), &message, &mut connection, &peer.addr)?
+        .map(|ctx| (connection.0, ctx)))
+}
+
+/// Limits each step of a handshake on `stream` to `HANDSHAKE_TIMEOUT`
+fn set_timeouts(stream: &std::net::TcpStream) -> Result<(), Error> {
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))
+        .and_then(|()| stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)))
+        .map_err(|e| convert_err(e, ErrCode::Network))
+}
+
+/// Declines a connection without waiting for the reply to be sent
+fn decline_in_background(arrived: Arrived, port: u16) {
+    task::spawn_blocking(move || decline(arrived.connection, &arrived.message, port));
+}
+
+/// Takes the outcome of a handshake run in the background;
+/// a panic in the task fails that handshake only
+fn joined<T>(result: Result<Result<T, Error>, JoinError>) -> Result<T, Error> {
+    result.unwrap_or_else(|e| Err(Error::new(ErrCode::Network, format!("handshake failed: {e}"))))
+}
+
+/// Runs blocking `f` on a thread of its own and waits for it
+async fn in_background<T: Send + 'static>(f: impl FnOnce() -> Result<T, Error> + Send + 'static) -> Result<T, Error> {
+    task::spawn_blocking(f).await.unwrap_or_else(|e| Err(convert_err(e, ErrCode::Fatal)))
+}
+
+/// Sleeps for `duration`, or forever if it is `None`
+async fn sleep_for(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reports that nothing has been heard from `peer` for too long
+fn went_offline(peer: SocketAddr) -> CloseCaused {
+    for action in state::timed_out(&State::Connected(peer)) {
+        if action == Action::Display(Notice::WentOffline) {
+            prompt("your peer went offline. Wait for them or leave");
+        }
+    }
+    // Back to waiting for the peer
+    CloseCaused::ByRemote
+}
+
+/// Reports that the session connection has broken
+fn connection_lost(e: &Error) -> CloseCaused {
+    prompt(&format!("connection to your peer is lost: {}. Wait for them or leave", e.descr));
+    CloseCaused::ByRemote
+}
+
+/// Binds the listener at `addr`.
+///
+/// An IPv6 listener also accepts IPv4 connections, whatever
+/// the system default is; they come from IPv4-mapped addresses.
+fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), socket2::Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    // As std does, so that a restarted simi can bind the port right away
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Address of the interface of the default route, IPv4 if there is one.
+///
+/// Connecting a UDP socket sends nothing, but makes the system choose
+/// the local address. The destinations are reserved for documentation.
+fn local_ip() -> Option<IpAddr> {
+    let route = |local: &str, remote: &str| {
+        let socket = UdpSocket::bind((local, 0)).ok()?;
+        socket.connect((remote, 9)).ok()?;
+        socket.local_addr().ok().map(|addr| addr.ip())
+    };
+    route("0.0.0.0", "192.0.2.1").or_else(|| route("::", "2001:db8::1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use std::sync::OnceLock;
+
+    use tokio::sync::{mpsc, Barrier};
+
+    use crate::proto::{recieve, send};
+    use crate::proto::tests::{assets, contexts, identity};
+    use super::*;
+
+    /// Application listening on a free local port with fresh assets,
+    /// fed with lines from `lines`
+    fn application(cfg: Config, lines: mpsc::UnboundedReceiver<Zeroizing<String>>) -> (Application, TempDir) {
+        let key = RsaPrivateKey::new(&mut thread_rng(), 512).unwrap();
+        application_with_key(cfg, key, lines)
+    }
+
+    /// Same as `application`, with the identity key `key`
+    fn application_with_key(
+        cfg: Config,
+        key: RsaPrivateKey,
+        lines: mpsc::UnboundedReceiver<Zeroizing<String>>
+    ) -> (Application, TempDir) {
+        let assets = assets();
+        let cfg = Config {
+            port: 0,
+            listen: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            assets: assets.path().to_str().unwrap().to_owned(),
+            ..cfg
+        };
+        let mut app = Application::initialize(cfg, Identity::ready(key), Input::from_lines(lines)).unwrap();
+        // Announced in handshakes
+        app.cfg.port = app.addr.port();
+        (app, assets)
+    }
+
+    /// Identity keys of two peers, large enough for handshakes;
+    /// generating them is slow
+    fn identities() -> &'static [RsaPrivateKey; 2] {
+        static KEYS: OnceLock<[RsaPrivateKey; 2]> = OnceLock::new();
+        KEYS.get_or_init(|| [identity().clone(), RsaPrivateKey::new(&mut thread_rng(), 2048).unwrap()])
+    }
+
+    /// Our messages collected by the peer, with the time each one arrived
+    type Collected = JoinHandle<Vec<(Instant, Message)>>;
+
+    /// A session with a peer that sends heartbeats and collects our messages
+    /// until the session is closed.
+    ///
+    /// Returns our side of the connection and the keys of both sides.
+    fn session_peer() -> (std::net::TcpStream, CryptoContext, CryptoContext, JoinHandle<()>, Collected) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (ctx, peer_ctx) = contexts();
+        let mut pulse = peer_ctx.pulse();
+        peer.set_nonblocking(true).unwrap();
+        let (mut reader, mut writer) = tokio::net::TcpStream::from_std(peer).unwrap().into_split();
+        let beating = tokio::spawn(async move {
+            while writer.write_all(&Message::new_heartbeat(4242, pulse.beat()).serialize().unwrap()).await.is_ok() {
+                sleep(HEARTBEAT_INTERVAL).await;
             }
-        } else {
-            if msg.t == Type::Request {
-                send(&mut connection.0, Message::new_deny(self.cfg.port)).unwrap();
+        });
+        let reading = tokio::spawn(async move {
+            let mut messages = Vec::new();
+            while let Ok(Some(msg)) = Message::read_from(&mut reader).await {
+                messages.push((Instant::now(), msg));
             }
+            messages
+        });
+        (stream, ctx, peer_ctx, beating, reading)
+    }
+
+    #[tokio::test]
+    async fn slow_secret_entry() {
+        let cfg = Config { heartbeat_timeout_seconds: 1, ..Config::default() };
+        let (typing, lines) = mpsc::unbounded_channel();
+        let (mut app, _assets) = application(cfg, lines);
+        let (stream, ctx, mut peer_ctx, beating, reading) = session_peer();
+        // Typing the secret takes longer than the heartbeat timeout
+        tokio::spawn(async move {
+            typing.send(Zeroizing::new("--secret".to_owned())).unwrap();
+            sleep(Duration::from_secs(2)).await;
+            typing.send(Zeroizing::new("typed slowly".to_owned())).unwrap();
+            sleep(2 * HEARTBEAT_INTERVAL).await;
+            typing.send(Zeroizing::new("--exit".to_owned())).unwrap();
+        });
+
+        let cause = app.connected_loop(stream, "Bob", ctx).await.unwrap();
+        assert!(cause == CloseCaused::Locally, "the session has ended while the secret was typed");
+        beating.abort();
+        let (_, secret) = reading.await.unwrap().into_iter()
+            .find(|(_, msg)| msg.t == Type::Speak)
+            .expect("the secret has been sent");
+        assert_eq!(decrypt_secret(&secret.data.unwrap(), &mut peer_ctx).unwrap().text.as_str(), "typed slowly");
+    }
+
+    #[tokio::test]
+    async fn forged_heartbeats() {
+        let cfg = Config { heartbeat_timeout_seconds: 1, ..Config::default() };
+        let (_typing, lines) = mpsc::unbounded_channel();
+        let (mut app, _assets) = application(cfg, lines);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (ctx, peer_ctx) = contexts();
+        // Someone on the path keeps the connection busy, but cannot
+        // authenticate the heartbeats of the peer, who is gone
+        let mut forger = Pulse::new(&[7; 32], &peer_ctx.session_id, peer_ctx.role);
+        peer.set_nonblocking(true).unwrap();
+        let mut peer = tokio::net::TcpStream::from_std(peer).unwrap();
+        let forging = tokio::spawn(async move {
+            while peer.write_all(&Message::new_heartbeat(4242, forger.beat()).serialize().unwrap()).await.is_ok() {
+                sleep(HEARTBEAT_INTERVAL / 2).await;
+            }
+        });
+
+        let limit = 2 * app.heartbeat_timeout(&ctx).unwrap();
+        let cause = tokio::time::timeout(limit, app.connected_loop(stream, "Bob", ctx)).await
+            .expect("forged heartbeats have kept the session alive");
+        assert!(cause.unwrap() == CloseCaused::ByRemote);
+        forging.abort();
+    }
+
+    #[tokio::test]
+    async fn heartbeats_while_sealing() {
+        let (typing, lines) = mpsc::unbounded_channel();
+        let (mut app, assets) = application(Config::default(), lines);
+        let cover = assets.path().join("large.png");
+        image::RgbImage::from_fn(1500, 1500, |_, _| image::Rgb(thread_rng().gen())).save(&cover).unwrap();
+        let (stream, ctx, mut peer_ctx, beating, reading) = session_peer();
+        for line in [format!("--secret --path={}", cover.display()), "hidden in a large image".to_owned(), "--exit".to_owned()] {
+            typing.send(Zeroizing::new(line)).unwrap();
         }
-        Ok(false)
+
+        let started = Instant::now();
+        assert!(app.connected_loop(stream, "Bob", ctx).await.unwrap() == CloseCaused::Locally);
+        beating.abort();
+        let messages = reading.await.unwrap();
+        let (sent, secret) = messages.iter()
+            .find(|(_, msg)| msg.t == Type::Speak)
+            .expect("the secret has been sent");
+        // Heartbeats have gone on while the image was worked on
+        let gaps = messages.iter().map(|(at, _)| *at)
+            .take_while(|at| at < sent)
+            .scan(started, |last, at| Some(at.duration_since(std::mem::replace(last, at))));
+        assert!(gaps.max().unwrap() < 3 * HEARTBEAT_INTERVAL);
+        let decrypted = decrypt_secret(secret.data.as_ref().unwrap(), &mut peer_ctx).unwrap();
+        assert_eq!(decrypted.text.as_str(), "hidden in a large image");
+    }
+
+    #[tokio::test]
+    async fn pending_rekey() {
+        let cfg = Config { rekey_after_minutes: 1, ..Config::default() };
+        let (_typing, lines) = mpsc::unbounded_channel();
+        let (app, _assets) = application(cfg, lines);
+        let (mut ctx, _) = contexts();
+        assert!(app.rekey_timeout(&ctx).is_some());
+        // Waiting for the peer's reply must not wake the session loop up over and over
+        ctx.start_rekey().unwrap();
+        assert!(ctx.rekey_pending());
+        assert!(app.rekey_timeout(&ctx).is_none());
+    }
+
+    #[tokio::test]
+    async fn huge_rekey_interval() {
+        let cfg = Config { rekey_after_minutes: u64::MAX, ..Config::default() };
+        let (_typing, lines) = mpsc::unbounded_channel();
+        let (app, _assets) = application(cfg, lines);
+        let (ctx, _) = contexts();
+        assert_eq!(app.rekey_interval(), Duration::from_secs(u64::MAX));
+        assert!(!ctx.rekey_due(0, app.rekey_interval()));
+        assert!(app.rekey_timeout(&ctx).is_some_and(|timeout| timeout > app.rekey_interval() / 2));
+    }
+
+    /// Waits for the peer at `addr` until a connection is chosen, then
+    /// declines other connections, as a session does, until `both` are connected.
+    ///
+    /// Returns the connection and what has been told of our dials.
+    async fn connect(app: &mut Application, addr: SocketAddr, both: &Barrier) -> (Established, Reported) {
+        let mut waiting = Waiting::new(app.peer(addr));
+        let established = app.wait_for_peer(&mut waiting).await.unwrap().expect("the peer has not connected");
+        let declining = async {
+            loop {
+                if let Ok(Event::Arrived(arrived)) = app.next_event().await {
+                    decline_in_background(arrived, app.cfg.port);
+                }
+            }
+        };
+        tokio::select! {
+            _ = both.wait() => (),
+            () = declining => (),
+        }
+        (established, waiting.reported)
+    }
+
+    #[tokio::test]
+    async fn simultaneous_open() {
+        let [alice_key, bob_key] = identities().clone();
+        let (_alice_typing, alice_lines) = mpsc::unbounded_channel();
+        let (_bob_typing, bob_lines) = mpsc::unbounded_channel();
+        let (mut alice, _alice_assets) = application_with_key(Config::default(), alice_key, alice_lines);
+        let (mut bob, _bob_assets) = application_with_key(Config::default(), bob_key, bob_lines);
+        let (alice_addr, bob_addr) = (alice.addr, bob.addr);
+
+        // Both dial at once
+        let both = Barrier::new(2);
+        let ((alice_connection, alice_reported), (bob_connection, bob_reported)) = tokio::join!(
+            connect(&mut alice, bob_addr, &both),
+            connect(&mut bob, alice_addr, &both));
+        let ((alice_stream, alice_ctx), (bob_stream, bob_ctx)) = (alice_connection, bob_connection);
+        // Both keep the same connection, and with it the same session
+        assert_eq!(alice_stream.local_addr().unwrap(), bob_stream.peer_addr().unwrap());
+        assert_eq!(alice_stream.peer_addr().unwrap(), bob_stream.local_addr().unwrap());
+        assert_eq!(alice_ctx.session_id, bob_ctx.session_id);
+        // Neither has taken the other for offline
+        assert_ne!(alice_reported, Reported::Offline);
+        assert_ne!(bob_reported, Reported::Offline);
+    }
+
+    #[tokio::test]
+    async fn tie_break_deny() {
+        let [ours, theirs] = identities().clone();
+        let (_typing, lines) = mpsc::unbounded_channel();
+        let (mut app, _assets) = application_with_key(Config::default(), ours, lines);
+        let suites = app.cfg.cipher_suites.clone();
+        let app_addr = app.addr;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        let mut waiting = Waiting::new(app.peer(peer_addr));
+        let peer = task::spawn_blocking(move || {
+            // Our dial is held...
+            let (mut dialed, from) = listener.accept().unwrap();
+            let request = recieve(&mut dialed).unwrap();
+            // ...while the peer dials us and we accept
+            let mut dialing = std::net::TcpStream::connect(app_addr).unwrap();
+            send(&mut dialing, Message::new_request(peer_addr.port(), RsaPublicKey::from(&theirs), suites)).unwrap();
+            assert_eq!(recieve(&mut dialing).unwrap().t, Type::Accept);
+            // Connected by now, the peer declines our dial
+            decline((dialed, from), &request, peer_addr.port());
+            dialing
+        });
+
+        let declined = async {
+            while waiting.reported == Reported::Nothing {
+                let _ = tokio::time::timeout(Duration::from_millis(50), app.wait_for_peer(&mut waiting)).await;
+            }
+        };
+        tokio::time::timeout(HANDSHAKE_TIMEOUT / 2, declined).await.expect("our dial has not been declined");
+        // The handshake of the peer's connection was in progress,
+        // so the denial is a tie-break, not the peer being offline
+        assert_eq!(waiting.reported, Reported::TieBreak);
+        drop(peer.await.unwrap());
     }
 }
@@ -0,0 +1,67 @@
+//! Delays between attempts to dial a peer we are waiting for.
+//!
+//! Delays grow exponentially up to a limit and are randomized, so that
+//! two peers waiting for each other do not keep dialing in lockstep.
+use std::time::Duration;
+
+use rand::{thread_rng, Rng};
+
+/// Delay before the first attempt to dial again
+const FIRST_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest delay between attempts
+// `Duration::from_mins` would need Rust 1.91
+#[allow(clippy::duration_suboptimal_units)]
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: FIRST_DELAY }
+    }
+}
+
+impl Backoff {
+    /// Delay before the next attempt; each call doubles the following one
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_DELAY);
+        // Anywhere between half of the delay and the whole of it
+        delay.mul_f64(thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Starts over from the shortest delay
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growth_and_cap() {
+        let mut backoff = Backoff::default();
+        let mut nominal = FIRST_DELAY;
+        for _ in 0..10 {
+            let delay = backoff.next_delay();
+            assert!(delay >= nominal / 2 && delay <= nominal, "{delay:?} is out of the bounds of {nominal:?}");
+            nominal = (nominal * 2).min(MAX_DELAY);
+        }
+        assert_eq!(nominal, MAX_DELAY);
+        backoff.reset();
+        assert!(backoff.next_delay() <= FIRST_DELAY);
+    }
+
+    #[test]
+    fn jitter() {
+        // Delays of peers waiting for each other must not stay in lockstep
+        let delays: Vec<_> = (0..20).map(|_| Backoff::default().next_delay()).collect();
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+        assert!(delays.iter().all(|delay| *delay >= FIRST_DELAY / 2 && *delay <= FIRST_DELAY));
+    }
+}
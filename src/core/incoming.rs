@@ -0,0 +1,258 @@
+//! Connections accepted from the listener whose first message
+//! has not arrived yet.
+//!
+//! Each connection is read by its own task, so that a peer that sends
+//! its message slowly or not at all delays neither the interface
+//! nor other connections.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+
+use crate::core::{debug_prompt, prompt};
+use crate::error::{Error, ErrCode, convert_err};
+use crate::proto::message::{Message, Type};
+
+/// Time given to a connection to send its first message
+#[cfg(not(test))]
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(test)]
+const FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of connections waiting for their first message at once;
+/// connections beyond it are dropped
+const MAX_PENDING: usize = 16;
+
+/// Number of connections from one source waiting at once, so that
+/// a single host cannot take up all the slots and lock real peers out
+const MAX_PENDING_PER_SOURCE: usize = 4;
+
+/// A connection that has sent its first message.
+///
+/// The connection is in blocking mode, as expected by the handshake.
+pub struct Arrived {
+    pub connection: (std::net::TcpStream, SocketAddr),
+    pub message: Message,
+}
+
+pub struct Incoming {
+    sender: UnboundedSender<Arrived>,
+    arrived: UnboundedReceiver<Arrived>,
+    /// One permit per connection that may be pending
+    slots: Arc<Semaphore>,
+    /// Number of pending connections of each source, see `source`
+    sources: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+/// Slot taken by a pending connection, freed when dropped
+struct Pending {
+    _slot: OwnedSemaphorePermit,
+    source: IpAddr,
+    sources: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut sources = self.sources.lock().unwrap();
+        if let Some(count) = sources.get_mut(&self.source) {
+            *count -= 1;
+            if *count == 0 {
+                sources.remove(&self.source);
+            }
+        }
+    }
+}
+
+impl Default for Incoming {
+    fn default() -> Self {
+        let (sender, arrived) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            arrived,
+            slots: Arc::new(Semaphore::new(MAX_PENDING)),
+            sources: Arc::default(),
+        }
+    }
+}
+
+impl Incoming {
+    /// Starts waiting for the first message of a connection
+    /// accepted from the listener
+    pub fn admit(&self, stream: TcpStream, addr: SocketAddr) {
+        let Some(pending) = self.reserve(source(addr.ip())) else {
+            debug_prompt(&format!("too many incoming connections, dropping {addr}"));
+            return;
+        };
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            let _pending = pending;
+            match read_first(stream).await {
+                Ok((stream, message)) => {
+                    // The receiver is gone only if we are exiting
+                    sender.send(Arrived { connection: (stream, addr), message }).ok();
+                }
+                // Otherwise the user would never learn why an old peer cannot reach them
+                Err(e) if e.code() == ErrCode::Incompatible =>
+                    prompt(&format!("connection from {addr} dropped: {}", e.descr)),
+                Err(e) => debug_prompt(&format!("connection from {addr} dropped: {}", e.descr)),
+            }
+        });
+    }
+
+    /// Takes a slot for a connection from `source`, unless all slots
+    /// or those of the source are taken
+    fn reserve(&self, source: IpAddr) -> Option<Pending> {
+        let mut sources = self.sources.lock().unwrap();
+        if sources.get(&source).is_some_and(|count| *count >= MAX_PENDING_PER_SOURCE) {
+            return None;
+        }
+        let slot = self.slots.clone().try_acquire_owned().ok()?;
+        *sources.entry(source).or_default() += 1;
+        Some(Pending { _slot: slot, source, sources: Arc::clone(&self.sources) })
+    }
+
+    /// Waits for the next connection whose first message has arrived
+    pub async fn next(&mut self) -> Arrived {
+        self.arrived.recv().await
+            .expect("the sender is kept alongside the receiver")
+    }
+}
+
+/// Source of connections from `ip`, whose pending connections are counted together.
+///
+/// A host is usually given a whole IPv6 /64 network,
+/// so IPv6 addresses are taken by their /64 prefix.
+fn source(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        v4 @ IpAddr::V4(_) => v4,
+    }
+}
+
+/// Whether a connection may start with a message of type `t`.
+///
+/// Sessions run on the connection of their handshake, so anything else
+/// is refused by the header, before a stranger makes us buffer its body.
+fn opens_connection(t: Type) -> bool {
+    matches!(t, Type::Request | Type::Noise)
+}
+
+/// Reads the first message of `stream` and switches it back to blocking mode
+async fn read_first(mut stream: TcpStream) -> Result<(std::net::TcpStream, Message), Error> {
+    let message = timeout(FIRST_MESSAGE_TIMEOUT, Message::read_expected(&mut stream, opens_connection)).await
+        .map_err(|_| Error::new(ErrCode::Network, "timed out".to_owned()))??
+        .ok_or_else(|| Error::new(ErrCode::Network, "connection closed".to_owned()))?;
+    let stream = stream.into_std().map_err(|e| convert_err(e, ErrCode::Network))?;
+    stream.set_nonblocking(false).map_err(|e| convert_err(e, ErrCode::Network))?;
+    Ok((stream, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpSocket};
+
+    use crate::proto::message::MSG_LIMIT;
+    use super::*;
+
+    /// Connects to `listener` from the loopback address `from`
+    /// and admits the accepted side to `incoming`
+    async fn connect(listener: &TcpListener, incoming: &Incoming, from: &str) -> TcpStream {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(SocketAddr::new(from.parse().unwrap(), 0)).unwrap();
+        let client = socket.connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+        incoming.admit(stream, addr);
+        client
+    }
+
+    /// Whether `client` is disconnected within `limit`
+    async fn dropped(client: &mut TcpStream, limit: Duration) -> bool {
+        matches!(timeout(limit, client.read(&mut [0])).await, Ok(Ok(0) | Err(_)))
+    }
+
+    #[tokio::test]
+    async fn silent_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut incoming = Incoming::default();
+        let mut client = connect(&listener, &incoming, "127.0.0.1").await;
+        assert!(!dropped(&mut client, FIRST_MESSAGE_TIMEOUT / 2).await);
+        assert!(dropped(&mut client, FIRST_MESSAGE_TIMEOUT).await);
+        assert!(timeout(Duration::from_millis(100), incoming.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unexpected_first_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut incoming = Incoming::default();
+        let mut client = connect(&listener, &incoming, "127.0.0.1").await;
+        // Only the header of a secret announcing the largest body there may be
+        let mut header = MSG_LIMIT.to_le_bytes().to_vec();
+        header.push(Type::Speak as u8);
+        client.write_all(&header).await.unwrap();
+        assert!(dropped(&mut client, FIRST_MESSAGE_TIMEOUT / 4).await);
+        // Nor does a heartbeat, which only belongs to a session
+        let mut client = connect(&listener, &incoming, "127.0.0.1").await;
+        client.write_all(&Message::new_heartbeat(4242, vec![0; 40]).serialize().unwrap()).await.unwrap();
+        assert!(dropped(&mut client, FIRST_MESSAGE_TIMEOUT / 4).await);
+        assert!(timeout(Duration::from_millis(100), incoming.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pending_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut incoming = Incoming::default();
+        let mut clients = Vec::new();
+        for i in 0..MAX_PENDING {
+            let from = format!("127.0.0.{}", 1 + i / MAX_PENDING_PER_SOURCE);
+            clients.push(connect(&listener, &incoming, &from).await);
+        }
+        // The connection beyond the limit is dropped at once, the others wait
+        let mut extra = connect(&listener, &incoming, "127.0.1.1").await;
+        assert!(dropped(&mut extra, FIRST_MESSAGE_TIMEOUT / 4).await);
+        assert!(!dropped(&mut clients[0], FIRST_MESSAGE_TIMEOUT / 4).await);
+        // A connection that has sent its message frees its slot
+        let message = Message { t: Type::Request, port: 4242, data: Some(vec![7; 16]) }.serialize().unwrap();
+        clients[0].write_all(&message).await.unwrap();
+        assert_eq!(incoming.next().await.message.port, 4242);
+        let mut client = connect(&listener, &incoming, "127.0.1.1").await;
+        client.write_all(&message).await.unwrap();
+        assert_eq!(incoming.next().await.message.port, 4242);
+    }
+
+    #[tokio::test]
+    async fn silent_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut incoming = Incoming::default();
+        // One host opens as many silent connections as it can
+        let mut silent = Vec::new();
+        for _ in 0..MAX_PENDING {
+            silent.push(connect(&listener, &incoming, "127.0.0.1").await);
+        }
+        assert!(dropped(&mut silent[MAX_PENDING_PER_SOURCE], FIRST_MESSAGE_TIMEOUT / 4).await);
+        assert!(!dropped(&mut silent[0], FIRST_MESSAGE_TIMEOUT / 4).await);
+        // A request from anyone else still gets through
+        let request = Message { t: Type::Request, port: 4242, data: Some(vec![7; 16]) };
+        let mut peer = connect(&listener, &incoming, "127.0.0.2").await;
+        peer.write_all(&request.serialize().unwrap()).await.unwrap();
+        let arrived = timeout(FIRST_MESSAGE_TIMEOUT / 2, incoming.next()).await.unwrap();
+        assert_eq!(arrived.message.t, Type::Request);
+        assert_eq!(arrived.connection.1.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn sources() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(source(ip("192.0.2.7")), ip("192.0.2.7"));
+        assert_eq!(source(ip("::ffff:192.0.2.7")), ip("192.0.2.7"));
+        // The addresses of one IPv6 network are a single source
+        assert_eq!(source(ip("2001:db8:1:2:aaaa::1")), ip("2001:db8:1:2::"));
+        assert_eq!(source(ip("2001:db8:1:2:bbbb::2")), source(ip("2001:db8:1:2:aaaa::1")));
+        assert_ne!(source(ip("2001:db8:1:3::1")), source(ip("2001:db8:1:2::1")));
+    }
+}
@@ -0,0 +1,73 @@
+//! Lines typed by the user.
+//!
+//! Stdin is read on a dedicated thread, so that the event loop can wait
+//! for a line along with network events. Every line, including passphrases
+//! and secrets, must be taken from here: reading stdin directly would race
+//! with that thread.
+use std::io::{stdin, stdout, Write};
+use std::thread;
+
+use colored::Colorize;
+use nix::libc::STDIN_FILENO;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use zeroize::Zeroizing;
+
+pub struct Input {
+    lines: UnboundedReceiver<Zeroizing<String>>,
+}
+
+impl Input {
+    /// Starts reading stdin in the background
+    pub fn start() -> Self {
+        let (sender, lines) = mpsc::unbounded_channel();
+        thread::spawn(move || loop {
+            let mut line = Zeroizing::new(String::new());
+            match stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    let len = line.trim_end_matches(['\n', '\r']).len();
+                    line.truncate(len);
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Self { lines }
+    }
+
+    /// Input made of the lines sent to `lines`
+    #[cfg(test)]
+    pub fn from_lines(lines: UnboundedReceiver<Zeroizing<String>>) -> Self {
+        Self { lines }
+    }
+
+    /// Waits for the next line, without the line terminator.
+    ///
+    /// Returns `None` once stdin is closed.
+    pub async fn line(&mut self) -> Option<Zeroizing<String>> {
+        self.lines.recv().await
+    }
+
+    /// Prints `str` and waits for a line typed with echo disabled.
+    ///
+    /// If stdin is not a terminal, the line is read as is.
+    /// An empty line is returned if stdin is closed.
+    pub async fn hidden(&mut self, str: &str) -> Zeroizing<String> {
+        print!("\r{}: {} ", "<simi>".yellow(), str);
+        stdout().flush().unwrap();
+        let saved = tcgetattr(STDIN_FILENO).ok();
+        if let Some(saved) = &saved {
+            let mut hidden = saved.clone();
+            hidden.local_flags.remove(LocalFlags::ECHO);
+            tcsetattr(STDIN_FILENO, SetArg::TCSANOW, &hidden).ok();
+        }
+        let line = self.line().await.unwrap_or_default();
+        if let Some(saved) = saved {
+            tcsetattr(STDIN_FILENO, SetArg::TCSANOW, &saved).ok();
+            println!();
+        }
+        line
+    }
+}
@@ -1,10 +1,15 @@
 use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use colored::Colorize;
-use std::cell::RefCell;
 
 pub mod application;
+pub mod backoff;
+pub mod incoming;
+pub mod input;
+pub mod session;
 
-thread_local! { static DEBUG_PRINT_ENABLED: RefCell<bool> = RefCell::new(false); }
+/// Shared by all threads, since handshakes run in the background
+static DEBUG_PRINT_ENABLED: AtomicBool = AtomicBool::new(false);
 
 /// don't forget to make this fn private
 pub fn prompt(str: &str) {
@@ -22,28 +27,33 @@ pub fn named_prompt(name: &str, contents: &str) {
     stdout().flush().unwrap();
 }
 
+/// Prints a message from `name` that came unencrypted, so anyone
+/// on the path could have read or forged it
+pub fn plain_prompt(name: &str, contents: &str) {
+    print!("\r[{}{}]: {}\n{}: ", name.green(), ", unencrypted".yellow(), contents, "[you]".cyan());
+    stdout().flush().unwrap();
+}
+
 pub fn debug_prompt(str: &str) {
-    DEBUG_PRINT_ENABLED.with(|enabled| {
-        if *enabled.borrow() {
-            print!("\r{}: {}\n{}:", "<simi>".magenta(), str.magenta(), "[you]".cyan());
-            stdout().flush().unwrap();
-        }
-    });
+    if DEBUG_PRINT_ENABLED.load(Ordering::Relaxed) {
+        print!("\r{}: {}\n{}:", "<simi>".magenta(), str.magenta(), "[you]".cyan());
+        stdout().flush().unwrap();
+    }
 }
 
 pub fn toggle_debug() {
-    DEBUG_PRINT_ENABLED.with(|enabled| {
-        let new_state = !*enabled.borrow();
-        *enabled.borrow_mut() = new_state;
-        if new_state {
-            prompt("debug info enabled");
-        } else {
-            prompt("debug info disabled");
-        }
-    })
+    let new_state = !DEBUG_PRINT_ENABLED.fetch_xor(true, Ordering::Relaxed);
+    if new_state {
+        prompt("debug info enabled");
+    } else {
+        prompt("debug info disabled");
+    }
 }
 
-pub fn secret_prompt(name: &str, contents: &str) {
-    print!("\r[{}]: {}\n{}: ", name.red(), contents, "[you]".cyan());
+/// Prints a secret from `name`; `signed` marks secrets
+/// whose signature has been verified
+pub fn secret_prompt(name: &str, contents: &str, signed: bool) {
+    let mark = if signed { ", signed" } else { "" };
+    print!("\r[{}{}]: {}\n{}: ", name.red(), mark.red(), contents, "[you]".cyan());
     stdout().flush().unwrap();
 }
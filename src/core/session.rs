@@ -0,0 +1,85 @@
+//! The connection carrying a session.
+//!
+//! Messages from the peer are read by a background task, so that waiting
+//! for the next one can be cancelled without losing a partly read frame.
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::error::{Error, ErrCode, convert_err};
+use crate::proto::message::Message;
+
+/// Time given to a message to be sent
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Session {
+    peer: SocketAddr,
+    writer: OwnedWriteHalf,
+    /// Messages read so far; a failed read ends the stream
+    messages: UnboundedReceiver<Result<Message, Error>>,
+    reader: JoinHandle<()>,
+}
+
+impl Session {
+    /// Takes over `stream`, the connection the handshake was made on
+    pub fn new(stream: std::net::TcpStream) -> Result<Self, Error> {
+        let peer = stream.peer_addr().map_err(|e| convert_err(e, ErrCode::Network))?;
+        stream.set_nonblocking(true).map_err(|e| convert_err(e, ErrCode::Network))?;
+        let stream = TcpStream::from_std(stream).map_err(|e| convert_err(e, ErrCode::Network))?;
+        let (mut reader, writer) = stream.into_split();
+        let (sender, messages) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(async move {
+            loop {
+                let message = match Message::read_from(&mut reader).await {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                let failed = message.is_err();
+                if sender.send(message).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Self { peer, writer, messages, reader })
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Sends `message` to the peer
+    pub async fn send(&mut self, message: Message) -> Result<(), Error> {
+        let frame = message.serialize()?;
+        timeout(WRITE_TIMEOUT, self.writer.write_all(&frame)).await
+            .map_err(|_| Error::new(ErrCode::Network, "timed out sending a message".to_owned()))?
+            .map_err(|e| convert_err(e, ErrCode::Network))
+    }
+
+    /// Waits for the next message from the peer.
+    ///
+    /// Returns `None` if the peer has closed the connection.
+    pub async fn recieve(&mut self) -> Option<Result<Message, Error>> {
+        self.messages.recv().await
+    }
+
+    /// Sends `farewell` to the peer and closes the connection.
+    ///
+    /// Failures are ignored: the peer will notice that the connection is closed anyway.
+    pub async fn close(mut self, farewell: Message) {
+        self.send(farewell).await.ok();
+        self.writer.shutdown().await.ok();
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
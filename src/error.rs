@@ -5,7 +5,7 @@ pub struct Error {
     pub descr: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ErrCode {
     /// This is not an error
     Ok,
@@ -29,6 +29,9 @@ pub enum ErrCode {
     /// Not necessary fatal
     Network,
 
+    /// The peer runs a version of the protocol we cannot talk to
+    Incompatible,
+
     /// Serialization error
     Serial,
 
@@ -45,6 +48,11 @@ impl Error {
     pub fn new(code: ErrCode, descr: String) -> Error {
         Error{code, descr}
     }
+
+    /// Kind of the error
+    pub fn code(&self) -> ErrCode {
+        self.code
+    }
 }
 
 /// Converts any other error to the internal error type
@@ -0,0 +1,480 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::FromRawFd;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::ffi::CString;
+use std::ptr;
+use std::mem::ManuallyDrop;
+use std::thread;
+
+use argon2::{Argon2, Algorithm, Params, Version};
+use nix::libc;
+use bincode::{self, Options};
+use rand::{thread_rng, Rng};
+use rsa::RsaPrivateKey;
+use serde::{Serialize, Deserialize};
+use tokio::sync::oneshot::{self, Receiver, error::TryRecvError};
+use zeroize::Zeroizing;
+
+use crate::config::canonicalize_home;
+use crate::core::prompt;
+use crate::core::input::Input;
+use crate::error::{Error, ErrCode, convert_err};
+use crate::proto::cipher::{CipherSuite, NONCE_LEN};
+
+const PATH_TO_KEYSTORE: &str = "~/.simi/identity.key";
+
+/// Keystore format version
+const KEYSTORE_VERSION: u16 = 1;
+
+/// Environment variable containing the passphrase
+pub const PASSPHRASE_VAR: &str = "SIMI_PASSPHRASE";
+
+/// Environment variable containing the number of a file descriptor
+/// the passphrase can be read from
+pub const PASSPHRASE_FD_VAR: &str = "SIMI_PASSPHRASE_FD";
+
+/// Number of attempts to enter the passphrase interactively
+const ATTEMPTS: usize = 3;
+
+const SUITE: CipherSuite = CipherSuite::ChaCha20Poly1305;
+
+/// Kind of identity key generated on the first start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum KeyType {
+    #[default]
+    #[serde(rename = "rsa-2048")]
+    Rsa2048,
+    #[serde(rename = "rsa-3072")]
+    Rsa3072,
+    #[serde(rename = "rsa-4096")]
+    Rsa4096,
+}
+
+impl KeyType {
+    /// Size of the modulus in bits
+    pub fn bits(self) -> usize {
+        match self {
+            KeyType::Rsa2048 => 2048,
+            KeyType::Rsa3072 => 3072,
+            KeyType::Rsa4096 => 4096,
+        }
+    }
+
+    /// Generates a new key of this type. May take a while
+    pub fn generate(self) -> Result<RsaPrivateKey, Error> {
+        RsaPrivateKey::new(&mut thread_rng(), self.bits())
+            .map_err(|e| convert_err(e, ErrCode::Fatal))
+    }
+}
+
+enum IdentityState {
+    Ready(Box<RsaPrivateKey>),
+    /// The key is being generated on a background thread
+    Pending(Receiver<Result<RsaPrivateKey, Error>>),
+    Failed(String),
+}
+
+/// Identity key, possibly still being generated
+pub struct Identity {
+    state: IdentityState,
+}
+
+impl Identity {
+    pub fn ready(key: RsaPrivateKey) -> Self {
+        Self { state: IdentityState::Ready(Box::new(key)) }
+    }
+
+    /// Generates the key on a background thread
+    /// and saves it under `passphrase` once it is ready
+    fn generate(key_type: KeyType, passphrase: Zeroizing<String>) -> Self {
+        let (sender, receiver) = oneshot::channel();
+        thread::spawn(move || {
+            let result = key_type.generate().and_then(|key| {
+                save(&key, &passphrase)?;
+                Ok(key)
+            });
+            match &result {
+                Ok(_) => prompt(&format!("identity key saved to {PATH_TO_KEYSTORE}")),
+                Err(e) => prompt(&format!("cannot create identity key: {}", e.descr)),
+            }
+            sender.send(result).ok();
+        });
+        Self { state: IdentityState::Pending(receiver) }
+    }
+
+    /// Returns the key if it is ready, without blocking
+    pub fn get(&mut self) -> Option<&RsaPrivateKey> {
+        if let IdentityState::Pending(receiver) = &mut self.state {
+            match receiver.try_recv() {
+                Ok(result) => self.finish(result),
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Closed) => self.state = IdentityState::Failed("key generation aborted".to_owned()),
+            }
+        }
+        self.key()
+    }
+
+    /// Waits until the key is ready
+    pub async fn wait(&mut self) -> Result<&RsaPrivateKey, Error> {
+        if let IdentityState::Pending(receiver) = &mut self.state {
+            prompt("waiting for the identity key to be generated...");
+            match receiver.await {
+                Ok(result) => self.finish(result),
+                Err(_) => self.state = IdentityState::Failed("key generation aborted".to_owned()),
+            }
+        }
+        match &self.state {
+            IdentityState::Ready(key) => Ok(key),
+            IdentityState::Failed(descr) => Err(Error::new(ErrCode::Fatal, descr.clone())),
+            IdentityState::Pending(_) => unreachable!(),
+        }
+    }
+
+    /// Returns the key if it has already been obtained
+    /// with `get` or `wait`
+    pub fn key(&self) -> Option<&RsaPrivateKey> {
+        match &self.state {
+            IdentityState::Ready(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    fn finish(&mut self, result: Result<RsaPrivateKey, Error>) {
+        self.state = match result {
+            Ok(key) => IdentityState::Ready(Box::new(key)),
+            Err(e) => IdentityState::Failed(e.descr),
+        };
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+/// On-disk representation of the identity key.
+///
+/// The private key is encrypted with a key derived from the passphrase
+/// using Argon2id. Derivation parameters are stored along with it and
+/// authenticated as associated data.
+struct Keystore {
+    version: u16,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: [u8; 16],
+    nonce: [u8; NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+impl Keystore {
+    fn seal(key: &RsaPrivateKey, passphrase: &str) -> Result<Self, Error> {
+        let mut rng = thread_rng();
+        let mut store = Keystore {
+            version: KEYSTORE_VERSION,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            salt: rng.gen(),
+            nonce: rng.gen(),
+            ciphertext: Vec::new(),
+        };
+        let plaintext = serializer()
+            .serialize(key)
+            .map(Zeroizing::new)
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        let wrapping_key = store.derive_key(passphrase)?;
+        store.ciphertext = SUITE.encrypt(wrapping_key.as_ref(), &store.nonce, &plaintext, &store.associated_data());
+        Ok(store)
+    }
+
+    fn open(&self, passphrase: &str) -> Result<RsaPrivateKey, Error> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(Error::new(
+                ErrCode::Fatal,
+                format!("unsupported keystore version {}", self.version)));
+        }
+        let wrapping_key = self.derive_key(passphrase)?;
+        let plaintext = SUITE.decrypt(wrapping_key.as_ref(), &self.nonce, &self.ciphertext, &self.associated_data())
+            .map(Zeroizing::new)
+            .map_err(|_| Error::new(ErrCode::WrongArgs, "wrong passphrase".to_owned()))?;
+        serializer()
+            .deserialize(&plaintext)
+            .map_err(|e| convert_err(e, ErrCode::Serial))
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, key.as_mut())
+            .map_err(|e| convert_err(e, ErrCode::Fatal))?;
+        Ok(key)
+    }
+
+    fn associated_data(&self) -> Vec<u8> {
+        let mut aad = b"simi keystore".to_vec();
+        aad.extend_from_slice(&self.version.to_le_bytes());
+        aad.extend_from_slice(&self.m_cost.to_le_bytes());
+        aad.extend_from_slice(&self.t_cost.to_le_bytes());
+        aad.extend_from_slice(&self.p_cost.to_le_bytes());
+        aad.extend_from_slice(&self.salt);
+        aad
+    }
+}
+
+fn serializer() -> impl Options {
+    bincode::DefaultOptions::new().with_little_endian()
+}
+
+fn keystore_path() -> Result<PathBuf, Error> {
+    canonicalize_home(PATH_TO_KEYSTORE)
+        .ok_or_else(|| Error::new(ErrCode::Filesys, "cannot locate home directory".to_owned()))
+}
+
+/// Returns `true` if the keystore file exists
+pub fn exists() -> bool {
+    canonicalize_home(PATH_TO_KEYSTORE).is_some_and(|path| path.exists())
+}
+
+/// Reads the keystore file and decrypts the identity key with `passphrase`
+pub fn load(passphrase: &str) -> Result<RsaPrivateKey, Error> {
+    load_from(&keystore_path()?, passphrase)
+}
+
+fn load_from(path: &Path, passphrase: &str) -> Result<RsaPrivateKey, Error> {
+    let raw = fs::read(path).map_err(|e| convert_err(e, ErrCode::Filesys))?;
+    let store: Keystore = serializer()
+        .deserialize(&raw)
+        .map_err(|e| convert_err(e, ErrCode::Serial))?;
+    store.open(passphrase)
+}
+
+/// Encrypts the identity key with `passphrase` and writes it
+/// to the keystore file, readable only by the current user
+pub fn save(key: &RsaPrivateKey, passphrase: &str) -> Result<(), Error> {
+    save_to(&keystore_path()?, key, passphrase)
+}
+
+fn save_to(path: &Path, key: &RsaPrivateKey, passphrase: &str) -> Result<(), Error> {
+    let raw = serializer()
+        .serialize(&Keystore::seal(key, passphrase)?)
+        .map_err(|e| convert_err(e, ErrCode::Serial))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| convert_err(e, ErrCode::Filesys))?;
+    }
+    // Write to a temporary file first, so that a failure
+    // does not leave us without a key
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| convert_err(e, ErrCode::Filesys))?;
+    file.write_all(&raw).map_err(|e| convert_err(e, ErrCode::Filesys))?;
+    file.sync_all().map_err(|e| convert_err(e, ErrCode::Filesys))?;
+    fs::rename(tmp_path, path).map_err(|e| convert_err(e, ErrCode::Filesys))
+}
+
+/// Reads the passphrase supplied for non-interactive use.
+///
+/// `SIMI_PASSPHRASE` takes precedence over `SIMI_PASSPHRASE_FD`;
+/// in the latter case the first line read from the descriptor is used.
+/// Returns `None` if neither variable is set.
+///
+/// Must be called before any other thread is started,
+/// as `SIMI_PASSPHRASE` is removed from the environment.
+pub fn passphrase_from_env() -> Result<Option<Zeroizing<String>>, Error> {
+    let passphrase = env::var(PASSPHRASE_VAR).ok().map(Zeroizing::new);
+    scrub_env(PASSPHRASE_VAR);
+    if passphrase.is_some() {
+        return Ok(passphrase);
+    }
+    let Ok(raw_fd) = env::var(PASSPHRASE_FD_VAR) else {
+        return Ok(None);
+    };
+    let fd = raw_fd.parse::<i32>()
+        .map_err(|_| Error::new(ErrCode::Fatal, format!("{PASSPHRASE_FD_VAR} is not a file descriptor")))?;
+    // Stdin is read by the input thread, stdout and stderr are our output
+    if (0..=2).contains(&fd) {
+        return Err(Error::new(ErrCode::Fatal, format!("{PASSPHRASE_FD_VAR} must not be a standard stream")));
+    }
+    // Safety: the descriptor stays open, as it belongs to whoever passed it
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    first_line(&mut *file).map(Some)
+}
+
+/// Reads `reader` byte by byte up to the first newline.
+///
+/// Nothing past the line is consumed, so a writer that keeps
+/// the descriptor open, or whatever follows the line, does not matter.
+fn first_line(reader: &mut impl Read) -> Result<Zeroizing<String>, Error> {
+    let mut line = Zeroizing::new(Vec::with_capacity(256));
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(convert_err(e, ErrCode::Fatal)),
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(std::mem::take(&mut *line))
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            // The rejected bytes are handed back, wipe them as well
+            drop(Zeroizing::new(e.into_bytes()));
+            Error::new(ErrCode::Fatal, format!("{PASSPHRASE_FD_VAR} passes a passphrase that is not UTF-8"))
+        })
+}
+
+/// Removes `name` from the environment, so that child processes
+/// do not inherit it.
+///
+/// The value is wiped in place first: /proc/self/environ shows
+/// the environment the process was started with, which unsetting
+/// a variable leaves as it was.
+fn scrub_env(name: &str) {
+    let c_name = CString::new(name).unwrap();
+    // Safety: `getenv` returns null or the value, which is writable memory
+    // of its own length. No other thread may read the environment meanwhile,
+    // which `passphrase_from_env` callers guarantee
+    unsafe {
+        let value = libc::getenv(c_name.as_ptr());
+        if !value.is_null() {
+            ptr::write_bytes(value, 0, libc::strlen(value));
+        }
+    }
+    env::remove_var(name);
+}
+
+/// Asks the user for a new passphrase twice until both entries match.
+///
+/// Returns `None` if they failed to do so in `ATTEMPTS` tries.
+pub async fn ask_new_passphrase(input: &mut Input) -> Option<Zeroizing<String>> {
+    for _ in 0..ATTEMPTS {
+        let passphrase = input.hidden("enter new passphrase:").await;
+        if passphrase.is_empty() {
+            prompt("passphrase must not be empty");
+            continue;
+        }
+        if *input.hidden("repeat passphrase:").await == *passphrase {
+            return Some(passphrase);
+        }
+        prompt("passphrases do not match");
+    }
+    None
+}
+
+/// Unlocks the identity key stored in the keystore.
+///
+/// `env_passphrase` is the one taken from the environment
+/// by `passphrase_from_env`; if there is none, the user is asked for it.
+/// If there is no keystore yet, a new key of `key_type` is generated
+/// in the background and saved under a new passphrase.
+pub async fn unlock_or_create(
+    key_type: KeyType,
+    env_passphrase: Option<Zeroizing<String>>,
+    input: &mut Input,
+) -> Result<Identity, Error> {
+    if !exists() {
+        prompt("no identity key found, a new one will be generated");
+        let passphrase = match env_passphrase {
+            Some(val) => val,
+            None => ask_new_passphrase(input).await
+                .ok_or_else(|| Error::new(ErrCode::Fatal, "no passphrase set".to_owned()))?,
+        };
+        prompt(&format!("generating {}-bit RSA key in the background...", key_type.bits()));
+        return Ok(Identity::generate(key_type, passphrase));
+    }
+    if let Some(passphrase) = env_passphrase {
+        return load(&passphrase).map(Identity::ready);
+    }
+    for _ in 0..ATTEMPTS {
+        match load(&input.hidden("enter passphrase to unlock identity key:").await) {
+            Ok(key) => return Ok(Identity::ready(key)),
+            Err(e) => prompt(&e.descr),
+        }
+    }
+    Err(Error::new(ErrCode::Fatal, "cannot unlock identity key".to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn key() -> RsaPrivateKey {
+        RsaPrivateKey::new(&mut thread_rng(), 512).unwrap()
+    }
+
+    /// Keystore file in a fresh temporary directory, removed with the directory
+    fn path() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        (dir, path)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, path) = path();
+        let key = key();
+        save_to(&path, &key, "correct horse").unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(load_from(&path, "correct horse").unwrap(), key);
+        let e = load_from(&path, "battery staple").unwrap_err();
+        assert_eq!(e.descr, "wrong passphrase");
+    }
+
+    #[test]
+    fn passphrase_change() {
+        let (_dir, path) = path();
+        let key = key();
+        save_to(&path, &key, "old").unwrap();
+        // As done by `passwd` once the current passphrase is checked
+        save_to(&path, &load_from(&path, "old").unwrap(), "new").unwrap();
+        assert!(load_from(&path, "old").is_err());
+        assert_eq!(load_from(&path, "new").unwrap(), key);
+    }
+
+    #[test]
+    fn passphrase_line() {
+        let (mut writer, mut reader) = UnixStream::pair().unwrap();
+        // The writer stays open and what follows the line is not UTF-8
+        writer.write_all(b"correct horse\r\n\xff\xfe").unwrap();
+        assert_eq!(*first_line(&mut reader).unwrap(), "correct horse");
+        let mut rest = [0u8; 2];
+        reader.read_exact(&mut rest).unwrap();
+        assert_eq!(rest, [0xff, 0xfe]);
+        assert_eq!(*first_line(&mut &b"battery staple"[..]).unwrap(), "battery staple");
+        assert!(first_line(&mut &b"\xff\n"[..]).is_err());
+    }
+
+    #[test]
+    fn tampering() {
+        let store = Keystore::seal(&key(), "secret").unwrap();
+        let tampered = |modify: fn(&mut Keystore)| {
+            let mut store = store.clone();
+            modify(&mut store);
+            store.open("secret").is_err()
+        };
+        assert!(store.open("secret").is_ok());
+        assert!(tampered(|store| store.ciphertext[0] ^= 1));
+        assert!(tampered(|store| *store.ciphertext.last_mut().unwrap() ^= 1));
+        assert!(tampered(|store| store.nonce[0] ^= 1));
+        assert!(tampered(|store| store.salt[0] ^= 1));
+        // Weaker parameters are bound to the ciphertext as well
+        assert!(tampered(|store| store.t_cost -= 1));
+        assert!(tampered(|store| store.version += 1));
+    }
+}
@@ -3,18 +3,45 @@
 
 use std::process;
 use colored::Colorize;
+use tokio::runtime;
+use zeroize::Zeroizing;
 
+mod card;
 mod cli;
 mod error;
 mod config;
 mod core;
+mod keystore;
 mod proto;
 
 use crate::config::Config;
 use crate::core::application::Application;
+use crate::core::input::Input;
 use crate::core::prompt;
 
 fn main() {
+    // Taken before any thread is started, as it scrubs the environment
+    let env_passphrase = match keystore::passphrase_from_env() {
+        Ok(val) => val,
+        Err(e) => {
+            prompt(&format!("fatal error: {}", e.descr.red()));
+            process::exit(1);
+        }
+    };
+    let runtime = match runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(val) => val,
+        Err(e) => {
+            prompt(&format!("fatal error: {}", e.to_string().red()));
+            process::exit(1);
+        }
+    };
+    runtime.block_on(run(env_passphrase));
+    // Handshakes still running in the background are not waited for
+    runtime.shutdown_background();
+    println!("{}: exiting...", "<simi>".yellow());
+}
+
+async fn run(env_passphrase: Option<Zeroizing<String>>) {
     let config = match Config::load() {
         Ok(val) => val,
         Err(e) => {
@@ -24,17 +51,23 @@ fn main() {
             Config::default()
         }
     };
-    prompt("initializing RSA keys...");
-    let mut app = match Application::initialize(config) {
+    let mut input = Input::start();
+    let identity = match keystore::unlock_or_create(config.identity_key, env_passphrase, &mut input).await {
+        Ok(val) => val,
+        Err(e) => {
+            prompt(&format!("fatal error: {}", e.descr.red()));
+            process::exit(1);
+        }
+    };
+    let mut app = match Application::initialize(config, identity, input) {
         Ok(val) => val,
         Err(e) => {
             prompt(&format!("fatal error: {}", e.descr.red()));
             process::exit(1);
         }
     };
-    if let Err(e) = app.run() {
+    if let Err(e) = app.run().await {
         prompt(&format!("fatal error: {}", e.descr.red()));
         process::exit(1);
     }
-    println!("{}: exiting...", "<simi>".yellow());
 }
@@ -0,0 +1,80 @@
+//! Supported AEAD cipher suites.
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes128Gcm, Aes256Gcm
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::{thread_rng, Rng};
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+use crate::error::{Error, ErrCode, convert_err};
+
+/// Length of a nonce, the same for all suites
+pub const NONCE_LEN: usize = 12;
+
+/// AEAD cipher used to encrypt messages within a session.
+///
+/// The suite is chosen in the handshake from the lists of suites
+/// supported by both peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    /// Length of the key in bytes
+    pub fn key_len(self) -> usize {
+        match self {
+            CipherSuite::Aes128Gcm => 16,
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 32,
+        }
+    }
+
+    /// Generates a random key of suitable length
+    pub fn generate_key(self) -> Zeroizing<Vec<u8>> {
+        let mut key = Zeroizing::new(vec![0u8; self.key_len()]);
+        thread_rng().fill(key.as_mut_slice());
+        key
+    }
+
+    /// Encrypts `plaintext` and authenticates it along with `aad`.
+    ///
+    /// Only the first `key_len()` bytes of `key` are used.
+    pub fn encrypt(self, key: &[u8], nonce: &[u8; NONCE_LEN], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let key = &key[..self.key_len()];
+        let payload = Payload { msg: plaintext, aad };
+        let nonce = nonce.into();
+        match self {
+            CipherSuite::Aes128Gcm => Aes128Gcm::new_from_slice(key).unwrap().encrypt(nonce, payload),
+            CipherSuite::Aes256Gcm => Aes256Gcm::new_from_slice(key).unwrap().encrypt(nonce, payload),
+            CipherSuite::ChaCha20Poly1305 =>
+                ChaCha20Poly1305::new_from_slice(key).unwrap().encrypt(nonce, payload),
+        }.unwrap()
+    }
+
+    /// Decrypts `ciphertext` produced by `encrypt` with the same key and `aad`.
+    ///
+    /// Only the first `key_len()` bytes of `key` are used.
+    pub fn decrypt(self, key: &[u8], nonce: &[u8; NONCE_LEN], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let key = &key[..self.key_len()];
+        let payload = Payload { msg: ciphertext, aad };
+        let nonce = nonce.into();
+        match self {
+            CipherSuite::Aes128Gcm => Aes128Gcm::new_from_slice(key).unwrap().decrypt(nonce, payload),
+            CipherSuite::Aes256Gcm => Aes256Gcm::new_from_slice(key).unwrap().decrypt(nonce, payload),
+            CipherSuite::ChaCha20Poly1305 =>
+                ChaCha20Poly1305::new_from_slice(key).unwrap().decrypt(nonce, payload),
+        }.map_err(|e| convert_err(e, ErrCode::Serial))
+    }
+}
+
+/// Picks the first suite from `ours` that is also present in `theirs`
+pub fn negotiate(ours: &[CipherSuite], theirs: &[CipherSuite]) -> Option<CipherSuite> {
+    ours.iter().copied().find(|suite| theirs.contains(suite))
+}
@@ -1,18 +1,40 @@
 use std::io::Read;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use serde::{Serialize, Deserialize};
 use bincode::{self, Options};
 use rsa::{RsaPrivateKey, RsaPublicKey, PaddingScheme};
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::error::{Error, ErrCode, convert_err};
+use super::cipher::CipherSuite;
 
 /// Message length that must not be exceeded.
 /// All incoming messages will be discarded if they are
-/// longer.
-pub const MSG_LIMIT: u64 = 1024 * 1024 * 16; // 16 MiB
+/// longer. Most types have lower limits, see `Type::limit`.
+pub const MSG_LIMIT: u32 = 1024 * 1024 * 16; // 16 MiB
+
+/// Length of a frame header: body length (`u32`) and message type (`u8`)
+pub const HEADER_LEN: usize = 5;
+
+/// Version of the protocol implemented by this build.
+///
+/// Must be bumped on every incompatible change of the handshake
+/// or message layout. Version 1 is the original protocol, which
+/// carries no version; version 2 frames messages and starts
+/// handshake payloads with `VersionInfo`.
+pub const PROTO_VERSION: u16 = 2;
+
+/// Oldest version of the protocol this build can talk to.
+///
+/// Handshake payloads start with `VersionInfo` and may only be
+/// extended by appending fields, so that peers running different
+/// versions can still read them. Raise this when older peers
+/// can no longer be served.
+pub const MIN_PROTO_VERSION: u16 = 2;
 
 
 /// Protocol message type
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Type {
     Request,
     Accept,
@@ -21,16 +43,174 @@ pub enum Type {
     Speak,
     SpeakPlain,
     Close,
+    /// Plain text message encrypted with the session keys
+    SpeakSealed,
+    /// Message of the Noise handshake
+    Noise,
+    /// Key update within a session
+    Rekey,
+    /// A secret has been recieved and decrypted
+    Ack,
+    /// A secret has been recieved, but could not be decrypted
+    Nack,
+    /// Sent periodically to show that the peer is still there
+    Heartbeat,
+}
+
+impl Type {
+    /// Largest body of a frame of this type
+    pub fn limit(self) -> u32 {
+        match self {
+            Type::Deny | Type::Heartbeat => 64,
+            Type::Close | Type::Rekey | Type::Ack | Type::Nack => 1024,
+            Type::Request | Type::Accept | Type::Confirm | Type::Noise => 8 * 1024,
+            Type::SpeakPlain | Type::SpeakSealed => 64 * 1024,
+            Type::Speak => MSG_LIMIT,
+        }
+    }
+}
+
+impl TryFrom<u8> for Type {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        Ok(match value {
+            0 => Type::Request,
+            1 => Type::Accept,
+            2 => Type::Deny,
+            3 => Type::Confirm,
+            4 => Type::Speak,
+            5 => Type::SpeakPlain,
+            6 => Type::Close,
+            7 => Type::SpeakSealed,
+            8 => Type::Noise,
+            9 => Type::Rekey,
+            10 => Type::Ack,
+            11 => Type::Nack,
+            12 => Type::Heartbeat,
+            _ => return Err(Error::new(ErrCode::Serial, format!("unknown message type {value}"))),
+        })
+    }
+}
+
+/// Optional features of the protocol.
+///
+/// A session uses only the features supported by both peers.
+/// Unknown bits are ignored, so new features can be added
+/// without breaking older peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// Acknowledgements of secrets
+    pub const ACK: Self = Self(1);
+    /// Key updates within a session
+    pub const REKEY: Self = Self(1 << 1);
+    /// Secrets signed with identity keys
+    pub const SIGNED_SECRETS: Self = Self(1 << 2);
+    /// Heartbeats within a session
+    pub const HEARTBEAT: Self = Self(1 << 3);
+    /// Features implemented by this build
+    pub const SUPPORTED: Self = Self(Self::ACK.0 | Self::REKEY.0 | Self::SIGNED_SECRETS.0 | Self::HEARTBEAT.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features present in both sets
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+/// Version and features of a peer, sent at the beginning
+/// of handshake and deny messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub version: u16,
+    /// Oldest version the peer can talk to
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl VersionInfo {
+    /// Our version and features
+    pub fn ours() -> Self {
+        Self {
+            version: PROTO_VERSION,
+            min_version: MIN_PROTO_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    /// Reads version information from the beginning of a handshake
+    /// or deny message payload, ignoring whatever follows it.
+    ///
+    /// Returns `None` if the payload carries no version
+    /// (e.g. it was sent by a version 1 peer)
+    pub fn peek(bytes: &[u8]) -> Option<Self> {
+        let options = bincode::DefaultOptions::new()
+            .with_little_endian()
+            .allow_trailing_bytes();
+        options.deserialize(bytes).ok()
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[..2].copy_from_slice(&self.version.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.min_version.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.capabilities.bits().to_le_bytes());
+        bytes
+    }
+}
+
+/// Why a handshake has been denied, sent after the version information
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DenyReason {
+    /// The peer is not waiting for us
+    #[default]
+    Busy,
+    /// The peer has checked our handshake message and refused it
+    Refused,
+    /// Pre-shared keys differ, or only one side has one
+    PreSharedKey,
+}
+
+/// What both sides of a handshake have announced in the clear
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub initiator: VersionInfo,
+    pub responder: VersionInfo,
+    /// Cipher suites offered by the initiator
+    pub offered: Vec<CipherSuite>,
+}
+
+impl Negotiated {
+    /// Features the session may use
+    pub fn capabilities(&self) -> Capabilities {
+        self.initiator.capabilities.intersection(self.responder.capabilities)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RequestPayload {
+    pub info: VersionInfo,
     pub pkey: RsaPublicKey,
+    /// Cipher suites supported by the initiator
+    pub suites: Vec<CipherSuite>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AcceptPayload {
+    pub info: VersionInfo,
     pub pkey: RsaPublicKey,
+    /// Cipher suite chosen by the responder
+    pub suite: CipherSuite,
     pub enc: Vec<u8>,
 }
 
@@ -38,11 +218,49 @@ pub struct AcceptPayload {
 pub struct RandAndKey {
     pub nonce: u64,
     pub session_key: Vec<u8>,
+    /// Sender's initial ratchet public key
+    pub ratchet_key: Vec<u8>,
+    /// Proof of knowledge of the pre-shared key, empty if there is none
+    pub psk_proof: Vec<u8>,
 }
 
+/// Plaintext of a secret
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretBody {
+    pub text: String,
+    /// Signature of the text made with the sender's identity key, if any
+    pub signature: Option<Vec<u8>>,
+}
 
-/// A single protocol message
+impl Drop for SecretBody {
+    fn drop(&mut self) {
+        self.text.zeroize();
+    }
+}
+
+/// Envelope of a Noise handshake message
 #[derive(Debug, Serialize, Deserialize)]
+pub struct NoisePayload {
+    pub info: VersionInfo,
+    /// Whether the sender has a pre-shared key configured for the peer
+    pub psk: bool,
+    /// Noise handshake message
+    pub handshake: Vec<u8>,
+}
+
+impl Drop for RandAndKey {
+    fn drop(&mut self) {
+        self.session_key.zeroize();
+    }
+}
+
+
+/// A single protocol message.
+///
+/// On the wire, a message is a frame: a header with the length
+/// of the body and the type, followed by the body carrying
+/// the port and the data.
+#[derive(Debug)]
 pub struct Message {
     pub t: Type,
     pub port: u16,
@@ -51,20 +269,36 @@ pub struct Message {
 
 impl Message {
     /// Creates an empty request message
-    pub fn new_request(port: u16, pkey: RsaPublicKey) -> Self {
-        let data = RequestPayload{pkey}.serialize().unwrap();
+    pub fn new_request(port: u16, pkey: RsaPublicKey, suites: Vec<CipherSuite>) -> Self {
+        let data = RequestPayload{info: VersionInfo::ours(), pkey, suites}.serialize().unwrap();
         Self { t: Type::Request, port, data: Some(data) }
     }
 
     /// Creates an empty request message
-    pub fn new_accept(port: u16, pkey: RsaPublicKey, enc: Vec<u8>) -> Self {
-        let data = AcceptPayload{ pkey, enc}.serialize().unwrap();
+    pub fn new_accept(port: u16, pkey: RsaPublicKey, suite: CipherSuite, enc: Vec<u8>) -> Self {
+        let data = AcceptPayload{info: VersionInfo::ours(), pkey, suite, enc}.serialize().unwrap();
         Self { t: Type::Accept, port, data: Some(data) }
     }
 
-    /// Creates an empty request message
-    pub fn new_deny(port: u16) -> Self {
-        Self { t: Type::Deny, port, data: None }
+    /// Creates a deny message carrying our version information and `reason`,
+    /// so that the peer can tell a busy host from an incompatible one
+    /// or from one that has refused the handshake
+    pub fn new_deny(port: u16, reason: DenyReason) -> Self {
+        let data = bincode::DefaultOptions::new()
+            .with_little_endian()
+            .serialize(&(VersionInfo::ours(), reason))
+            .unwrap();
+        Self { t: Type::Deny, port, data: Some(data) }
+    }
+
+    /// Reason given in a deny message; `Busy` if there is none
+    pub fn deny_reason(&self) -> DenyReason {
+        bincode::DefaultOptions::new()
+            .with_little_endian()
+            .allow_trailing_bytes()
+            .deserialize::<(VersionInfo, DenyReason)>(self.data.as_deref().unwrap_or_default())
+            .map(|(_, reason)| reason)
+            .unwrap_or_default()
     }
 
     /// Creates an empty request message
@@ -77,42 +311,193 @@ impl Message {
         Self { t: Type::SpeakPlain, port, data: Some(payload) }
     }
 
+    /// Creates a plain text message; `sealed` must be the text
+    /// sealed with the session context
+    pub fn new_speak_sealed(port: u16, sealed: Vec<u8>) -> Self {
+        Self { t: Type::SpeakSealed, port, data: Some(sealed) }
+    }
+
     pub fn new_speak(port: u16, payload: Vec<u8>) -> Self {
         Self {t: Type::Speak, port, data: Some(payload)}
     }
 
-    /// Creates an empty request message
-    pub fn new_close(port: u16, nonce: u64) -> Self {
-        let data = bincode::DefaultOptions::new()
+    /// Creates a close message. `sealed` must be an empty payload
+    /// sealed with the session context, so that the peer can check
+    /// that the message comes from us
+    pub fn new_close(port: u16, sealed: Vec<u8>) -> Self {
+        Self { t: Type::Close, port, data: Some(sealed) }
+    }
+
+    /// Creates a key update message; `sealed` must be our new key
+    /// sealed with the session context
+    pub fn new_rekey(port: u16, sealed: Vec<u8>) -> Self {
+        Self { t: Type::Rekey, port, data: Some(sealed) }
+    }
+
+    /// Creates an acknowledgement; `sealed` must be the identifier
+    /// of the secret sealed with the session context
+    pub fn new_ack(port: u16, sealed: Vec<u8>) -> Self {
+        Self { t: Type::Ack, port, data: Some(sealed) }
+    }
+
+    /// Creates a negative acknowledgement; `sealed` must be the identifier
+    /// of the secret sealed with the session context, or `None`
+    /// if there is no session with the sender
+    pub fn new_nack(port: u16, sealed: Option<Vec<u8>>) -> Self {
+        Self { t: Type::Nack, port, data: sealed }
+    }
+
+    /// Creates a heartbeat; `pulse` is its counter and MAC,
+    /// which are all it carries
+    pub fn new_heartbeat(port: u16, pulse: Vec<u8>) -> Self {
+        Self { t: Type::Heartbeat, port, data: Some(pulse) }
+    }
+
+    /// Creates a message of the Noise handshake
+    pub fn new_noise(port: u16, psk: bool, handshake: Vec<u8>) -> Self {
+        let data = NoisePayload{info: VersionInfo::ours(), psk, handshake}.serialize().unwrap();
+        Self { t: Type::Noise, port, data: Some(data) }
+    }
+
+    /// Serializes the message into a frame so that it can be sent.
+    ///
+    /// The body must not exceed the limit of the message type.
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        let body = bincode::DefaultOptions::new()
             .with_little_endian()
-            .serialize(&nonce)
-            .unwrap();
-        Self { t: Type::Close, port, data: Some(data) }
+            .serialize(&(self.port, &self.data))
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        let len = u32::try_from(body.len()).ok()
+            .filter(|len| *len <= self.t.limit())
+            .ok_or_else(|| oversize(self.t, body.len()))?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + body.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(self.t as u8);
+        frame.extend_from_slice(&body);
+        Ok(frame)
+    }
+
+    /// Attempts to read a message from the given reader (usually, a TCP socket).
+    ///
+    /// Frames longer than the limit of their type are rejected
+    /// before the body is read. The body is buffered as it arrives,
+    /// so a length in the header alone allocates nothing.
+    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(|e| convert_err(e, ErrCode::Serial))?;
+        let (t, len) = parse_header(header)?;
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body).map_err(|e| convert_err(e, ErrCode::Serial))?;
+        if body.len() < len {
+            return Err(truncated(ErrCode::Serial));
+        }
+        Self::from_body(t, &body)
+    }
+
+    /// Reads a message from an asynchronous reader, such as the session connection.
+    ///
+    /// Returns `None` if the connection is closed before a frame starts.
+    /// Frames longer than the limit of their type are rejected
+    /// before the body is read.
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<Self>, Error> {
+        Self::read_expected(reader, |_| true).await
+    }
+
+    /// Like `read_from`, but a frame whose type is not `expected`
+    /// is rejected by its header, before the body is read.
+    ///
+    /// The body is buffered as it arrives, so a sender cannot make us
+    /// allocate more than it has actually sent.
+    pub async fn read_expected<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        expected: impl Fn(Type) -> bool
+    ) -> Result<Option<Self>, Error> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match reader.read(&mut header[filled..]).await.map_err(|e| convert_err(e, ErrCode::Network))? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(truncated(ErrCode::Network)),
+                n => filled += n,
+            }
+        }
+        let (t, len) = parse_header(header)?;
+        if !expected(t) {
+            return Err(Error::new(ErrCode::Network, format!("unexpected {t:?} message")));
+        }
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body).await.map_err(|e| convert_err(e, ErrCode::Network))?;
+        if body.len() < len {
+            return Err(truncated(ErrCode::Network));
+        }
+        Self::from_body(t, &body).map(Some)
     }
 
-    /// Serializes the message so that it can be sent.
-    /// 
-    /// Total length must not exceed `MSG_LIMIT` constant.
+    fn from_body(t: Type, body: &[u8]) -> Result<Self, Error> {
+        let (port, data) = bincode::DefaultOptions::new()
+            .with_little_endian()
+            .deserialize(body)
+            .map_err(|e| convert_err(e, ErrCode::Serial))?;
+        Ok(Self { t, port, data })
+    }
+}
+
+/// Reads the type and the body length from a frame header
+/// and checks the length against the limit of the type
+fn parse_header(header: [u8; HEADER_LEN]) -> Result<(Type, usize), Error> {
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    match Type::try_from(header[4]) {
+        Ok(t) if len <= t.limit() => Ok((t, len as usize)),
+        // Only a header that is invalid anyway is taken for a version 1 request
+        _ if legacy_request(header) => Err(Error::new(
+            ErrCode::Incompatible,
+            format!("peer runs incompatible simi version 1, version {MIN_PROTO_VERSION} or newer is required"))),
+        Ok(t) => Err(oversize(t, len as usize)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether `header` is the beginning of a request sent by version 1,
+/// which had no framing: the message was encoded with bincode as is,
+/// starting with the type, the port and the tag of `data`
+fn legacy_request(header: [u8; HEADER_LEN]) -> bool {
+    match header {
+        // Ports from 251 on take a marker and two bytes
+        [0, 251, _, _, 1] => true,
+        [0, port, 1, ..] => port < 251,
+        _ => false,
+    }
+}
+
+fn truncated(code: ErrCode) -> Error {
+    Error::new(code, "connection closed in the middle of a message".to_owned())
+}
+
+fn oversize(t: Type, len: usize) -> Error {
+    Error::new(
+        ErrCode::Serial,
+        format!("{t:?} message of {len} bytes exceeds the limit of {} bytes", t.limit()))
+}
+
+impl RequestPayload {
     pub fn serialize(&self) -> Result<Vec<u8>, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
-            .with_limit(MSG_LIMIT)
             .serialize(self)
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 
-    /// Attempts to read a message from the given reader (usually, a TCP socket)
-    /// with memory exhaustion protection.
-    pub fn deserialize<R: Read>(reader: &mut R) -> Result<Self, Error> {
+    /// Fields appended by newer versions are ignored
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
-            .with_limit(MSG_LIMIT)
-            .deserialize_from(reader)
+            .allow_trailing_bytes()
+            .deserialize(bytes)
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 }
 
-impl RequestPayload {
+impl AcceptPayload {
     pub fn serialize(&self) -> Result<Vec<u8>, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
@@ -120,15 +505,34 @@ impl RequestPayload {
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 
+    /// Fields appended by newer versions are ignored
     pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
+            .allow_trailing_bytes()
             .deserialize(bytes)
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 }
 
-impl AcceptPayload {
+impl SecretBody {
+    pub fn serialize(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
+        bincode::DefaultOptions::new()
+            .with_little_endian()
+            .serialize(self)
+            .map(Zeroizing::new)
+            .map_err(|e| convert_err(e, ErrCode::Serial))
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
+        bincode::DefaultOptions::new()
+            .with_little_endian()
+            .deserialize(bytes)
+            .map_err(|e| convert_err(e, ErrCode::Serial))
+    }
+}
+
+impl NoisePayload {
     pub fn serialize(&self) -> Result<Vec<u8>, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
@@ -136,19 +540,22 @@ impl AcceptPayload {
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 
+    /// Fields appended by newer versions are ignored
     pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
+            .allow_trailing_bytes()
             .deserialize(bytes)
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 }
 
 impl RandAndKey {
-    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+    pub fn serialize(&self) -> Result<Zeroizing<Vec<u8>>, Error> {
         bincode::DefaultOptions::new()
             .with_little_endian()
             .serialize(self)
+            .map(Zeroizing::new)
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 
@@ -159,13 +566,100 @@ impl RandAndKey {
             .map_err(|e| convert_err(e, ErrCode::Serial))
     }
 
+    /// Decrypts and deserializes the payload.
+    ///
+    /// Padding and parsing failures are reported with the same error,
+    /// so that callers cannot be turned into a decryption oracle.
     pub fn from_ciphertext(
         key: &RsaPrivateKey,
         padding: PaddingScheme,
         ciphertext: &[u8]
     ) -> Result<Self, Error> {
-        let bytes = key.decrypt(padding, ciphertext)
-            .map_err(|e| convert_err(e, ErrCode::Serial))?;
-        Self::deserialize(&bytes)
+        key.decrypt(padding, ciphertext)
+            .ok()
+            .map(Zeroizing::new)
+            .and_then(|bytes| Self::deserialize(&bytes).ok())
+            .ok_or_else(|| Error::new(ErrCode::Network, "handshake failed".to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(t: Type, len: u32) -> [u8; HEADER_LEN] {
+        let mut header = [t as u8; HEADER_LEN];
+        header[..4].copy_from_slice(&len.to_le_bytes());
+        header
     }
-}
\ No newline at end of file
+
+    /// Reads a message from a stream that ends right after `header`
+    fn read_header(header: [u8; HEADER_LEN]) -> Error {
+        Message::deserialize(&mut header.as_slice()).unwrap_err()
+    }
+
+    #[test]
+    fn over_limit() {
+        for (t, len) in [
+            (Type::Heartbeat, 65),
+            (Type::Deny, 65),
+            (Type::Ack, 1025),
+            (Type::Noise, 8 * 1024 + 1),
+            (Type::SpeakSealed, 64 * 1024 + 1),
+            (Type::Speak, MSG_LIMIT + 1),
+            (Type::Speak, u32::MAX),
+        ] {
+            // Rejected by the header alone, without waiting for the body
+            let e = read_header(header(t, len));
+            assert!(e.descr.contains("exceeds the limit"), "{t:?} of {len} bytes: {}", e.descr);
+        }
+    }
+
+    #[test]
+    fn at_limit() {
+        for t in [Type::Heartbeat, Type::Nack, Type::Request, Type::SpeakPlain, Type::Speak] {
+            // The header is accepted, then the body is missing
+            let e = read_header(header(t, t.limit()));
+            assert!(!e.descr.contains("exceeds the limit"), "{t:?}: {}", e.descr);
+        }
+    }
+
+    #[test]
+    fn truncated_body() {
+        // Only what has arrived is buffered, whatever the header says
+        let mut frame = header(Type::Speak, MSG_LIMIT).to_vec();
+        frame.extend_from_slice(&[0; 100]);
+        let e = Message::deserialize(&mut frame.as_slice()).unwrap_err();
+        assert!(e.descr.contains("in the middle of a message"), "{}", e.descr);
+    }
+
+    #[test]
+    fn unknown_type() {
+        assert!(Message::deserialize(&mut [0, 0, 0, 0, 13].as_slice()).is_err());
+    }
+
+    /// Message as version 1 encoded it
+    #[derive(Serialize)]
+    struct Legacy {
+        t: u32,
+        port: u16,
+        data: Option<Vec<u8>>,
+    }
+
+    #[test]
+    fn version_1_request() {
+        for port in [80, 4001] {
+            let request = Legacy { t: Type::Request as u32, port, data: Some(vec![7; 300]) };
+            let bytes = bincode::DefaultOptions::new().with_little_endian().serialize(&request).unwrap();
+            let e = Message::deserialize(&mut bytes.as_slice()).unwrap_err();
+            assert_eq!(e.code(), ErrCode::Incompatible, "port {port}: {}", e.descr);
+        }
+        // A valid frame that starts the same way is read as usual:
+        // the body takes the port, the tag, 5 bytes of length and the data
+        let speak = Message::new_speak(4001, vec![7; 0x10500 - 9]);
+        let frame = speak.serialize().unwrap();
+        assert_eq!(frame[..3], [0, 5, 1]);
+        let read = Message::deserialize(&mut frame.as_slice()).unwrap();
+        assert_eq!((read.t, read.port, read.data), (Type::Speak, 4001, speak.data));
+    }
+}
@@ -3,29 +3,369 @@ use std::path::PathBuf;
 use std::fs;
 use std::{io::Write, net::SocketAddr};
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use rand::{thread_rng, Rng};
-use rsa::{PublicKey, RsaPrivateKey, RsaPublicKey, PaddingScheme};
-use aes_gcm::{
-    aead::{KeyInit, Key, Aead},
-    aes::Aes128, Aes128Gcm, Nonce
-};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use rsa::{PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey, PaddingScheme};
+use rsa::pkcs1::EncodeRsaPublicKey;
 use image::RgbImage;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use x25519_dalek::StaticSecret;
+use zeroize::Zeroizing;
 
 use crate::error::{Error, ErrCode, convert_err};
 use crate::core::debug_prompt;
 
+pub mod cipher;
 pub mod lsb;
 pub mod message;
-use message::{Message, Type, AcceptPayload, RandAndKey};
+pub mod noise;
+pub mod psk;
+pub mod pulse;
+pub mod ratchet;
+pub mod rekey;
+pub mod sign;
+pub mod replay;
+pub mod state;
+use message::{Message, Type, AcceptPayload, RandAndKey, SecretBody, PROTO_VERSION, MIN_PROTO_VERSION};
+use message::{Capabilities, DenyReason, Negotiated, VersionInfo};
+use cipher::CipherSuite;
+use ratchet::Ratchet;
+use replay::ReplayWindow;
+use state::{Action, Notice, State, Verdict};
 
 use self::message::RequestPayload;
 
-#[derive(Debug)]
+/// Side of the handshake taken by a peer.
+///
+/// Used to tell the two directions of a session apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+}
+
+/// Parameters of an established session.
+///
+/// Key material is wiped from memory when the context is dropped
+/// and is never printed by `Debug`.
 pub struct CryptoContext {
     pub peer_public_key: RsaPublicKey,
-    pub session_key: Key<Aes128>,
+    /// Cipher suite negotiated in the handshake
+    pub suite: CipherSuite,
+    pub session_key: Zeroizing<Vec<u8>>,
     pub nonce: u64,
+    /// Our side of the handshake
+    pub role: Role,
+    /// Identifier derived from the session key, bound to every sealed message
+    pub session_id: [u8; 16],
+    /// Source of per-message keys for secrets
+    pub ratchet: Ratchet,
+    /// Counter of the last message sent in this session
+    pub send_counter: u64,
+    /// Counters of messages recieved in this session
+    pub recv_window: ReplayWindow,
+    /// Time the current keys were established
+    pub established: Instant,
+    /// Number of messages sealed or opened with the current keys
+    pub messages: u64,
+    /// Our key of a key update we have requested, if any
+    pending_rekey: Option<StaticSecret>,
+    /// Context replaced by the last key update, kept to open
+    /// messages that were in flight during the switch
+    previous: Option<Box<CryptoContext>>,
+    /// Versions and features announced in the handshake
+    pub negotiated: Negotiated,
+}
+
+impl std::fmt::Debug for CryptoContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CryptoContext")
+            .field("suite", &self.suite)
+            .field("session_key", &"<redacted>")
+            .field("role", &self.role)
+            .field("ratchet", &self.ratchet)
+            .field("send_counter", &self.send_counter)
+            .field("messages", &self.messages)
+            .field("rekey_pending", &self.pending_rekey.is_some())
+            .field("capabilities", &self.capabilities())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CryptoContext {
+    fn new(
+        peer_public_key: RsaPublicKey,
+        suite: CipherSuite,
+        session_key: Zeroizing<Vec<u8>>,
+        nonce: u64,
+        role: Role,
+        ratchet: Ratchet,
+        negotiated: Negotiated,
+    ) -> Self {
+        // Version information and cipher suites are sent in the clear; binding
+        // them to the session makes every sealed message fail if they have
+        // been tampered with, e.g. to make the peers choose a weaker suite.
+        // The offer comes last, so it needs no length
+        let digest = Sha256::new()
+            .chain_update(b"simi session id")
+            .chain_update(session_key.as_slice())
+            .chain_update(nonce.to_le_bytes())
+            .chain_update(negotiated.initiator.to_bytes())
+            .chain_update(negotiated.responder.to_bytes())
+            .chain_update([suite as u8])
+            .chain_update(negotiated.offered.iter().map(|&offered| offered as u8).collect::<Vec<_>>())
+            .finalize();
+        let mut session_id = [0u8; 16];
+        session_id.copy_from_slice(&digest[..16]);
+        Self {
+            peer_public_key,
+            suite,
+            session_key,
+            nonce,
+            role,
+            session_id,
+            ratchet,
+            send_counter: 0,
+            recv_window: ReplayWindow::default(),
+            established: Instant::now(),
+            messages: 0,
+            pending_rekey: None,
+            previous: None,
+            negotiated,
+        }
+    }
+
+    /// Authentication of the heartbeats of the session
+    pub fn pulse(&self) -> pulse::Pulse {
+        pulse::Pulse::new(&self.session_key, &self.session_id, self.role)
+    }
+
+    /// Features supported by both peers
+    pub fn capabilities(&self) -> Capabilities {
+        self.negotiated.capabilities()
+    }
+
+    /// Associated data authenticated with every sealed message:
+    /// session identifier, direction, message type and counter
+    fn associated_data(&self, sender: Role, t: Type, counter: u64) -> Vec<u8> {
+        let mut aad = self.session_id.to_vec();
+        aad.push(sender as u8);
+        aad.push(t as u8);
+        aad.extend_from_slice(&counter.to_le_bytes());
+        aad
+    }
+
+    /// Encrypts `plaintext` of a message of type `t` with the next message key.
+    ///
+    /// The result is prefixed with a message counter. The counter, the type
+    /// and the direction are authenticated, so that the peer can detect
+    /// replayed, reflected or spliced messages.
+    pub fn seal(&mut self, t: Type, plaintext: &[u8]) -> Vec<u8> {
+        self.send_counter += 1;
+        self.messages += 1;
+        let aad = self.associated_data(self.role, t, self.send_counter);
+        let mut result = self.send_counter.to_le_bytes().to_vec();
+        result.append(&mut self.ratchet.encrypt(plaintext, &aad));
+        result
+    }
+
+    /// Decrypts a payload of a message of type `t` produced by the peer's `seal`.
+    ///
+    /// Duplicated and stale messages are rejected. Messages sealed
+    /// with the keys replaced by the last key update are still accepted
+    /// until the first message sealed with the new keys arrives.
+    pub fn open(&mut self, t: Type, payload: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        match self.open_current(t, payload) {
+            Ok(plaintext) => {
+                self.previous = None;
+                Ok(plaintext)
+            }
+            Err(e) => match self.previous.as_mut() {
+                Some(previous) => previous.open_current(t, payload).map_err(|_| e),
+                None => Err(e),
+            },
+        }
+    }
+
+    fn open_current(&mut self, t: Type, payload: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        if payload.len() < 8 {
+            return Err(Error::new(ErrCode::Serial, "truncated message".to_owned()));
+        }
+        let (counter, body) = payload.split_at(8);
+        let counter = u64::from_le_bytes(counter.try_into().unwrap());
+        self.recv_window.check(counter)?;
+        let aad = self.associated_data(self.role.peer(), t, counter);
+        let plaintext = self.ratchet.decrypt(body, &aad)?;
+        self.recv_window.accept(counter);
+        self.messages += 1;
+        Ok(plaintext)
+    }
+}
+
+/// Kind of handshake used to establish a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HandshakeMode {
+    /// Request/Accept/Confirm exchange with RSA-encrypted session keys
+    #[default]
+    Rsa,
+    /// Noise `XX` handshake, see the `noise` module
+    Noise,
+}
+
+/// Local parameters of a handshake
+pub struct Handshake<'a> {
+    /// Handshake to run when dialing; both kinds are accepted
+    pub mode: HandshakeMode,
+    /// Port we are listening on
+    pub port: u16,
+    pub private_key: &'a RsaPrivateKey,
+    /// Cipher suites we agree to use, most preferred first
+    pub suites: &'a [CipherSuite],
+    /// Pre-shared key configured for the peer, if any
+    pub psk: Option<&'a [u8]>,
+    /// Fingerprint of the peer's identity key pinned in the contact list, if any
+    pub pinned: Option<&'a str>,
+    /// Set once the session is no longer wanted, e.g. because another
+    /// connection to the peer carries it. Checked by the initiator
+    /// before its last message, which the peer takes for establishing the session
+    pub abandoned: Option<&'a AtomicBool>,
+}
+
+impl Handshake<'_> {
+    fn abandoned(&self) -> bool {
+        self.abandoned.is_some_and(|abandoned| abandoned.load(Ordering::SeqCst))
+    }
+}
+
+/// Fingerprint of an identity key: SHA-256 of its PKCS#1 encoding
+pub fn fingerprint(key: &RsaPublicKey) -> String {
+    let der = key.to_pkcs1_der().unwrap();
+    format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(der.as_bytes())))
+}
+
+/// Whether `a` and `b` are the same address.
+///
+/// Dual-stack sockets see IPv4 peers at IPv4-mapped IPv6 addresses
+/// (`::ffff:a.b.c.d`), which are taken to be the IPv4 addresses they map.
+pub fn same_addr(a: &SocketAddr, b: &SocketAddr) -> bool {
+    canonical(*a) == canonical(*b)
+}
+
+/// `addr` with an IPv4-mapped IPv6 address replaced by the IPv4 one
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Address a connection comes from, with the port the sender
+/// announced as its listening one in the first message
+fn announced(connection: &(TcpStream, SocketAddr), request: &Message) -> SocketAddr {
+    SocketAddr::new(connection.1.ip(), request.port)
+}
+
+/// Smallest identity key accepted from a peer, in bits.
+/// Handshake secrets encrypted to smaller keys don't fit the OAEP padding
+const MIN_PEER_KEY_BITS: usize = 2048;
+
+/// Checks the size of the peer's identity key and its pinned fingerprint
+fn check_peer_key(params: &Handshake, key: &RsaPublicKey) -> Result<(), Error> {
+    let bits = key.size() * 8;
+    if bits < MIN_PEER_KEY_BITS {
+        return Err(Error::new(
+            ErrCode::Network,
+            format!("peer's identity key of {bits} bits is too small, at least {MIN_PEER_KEY_BITS} are required")));
+    }
+    check_pinned(params, key)
+}
+
+/// Checks the peer's identity key against the pinned fingerprint
+fn check_pinned(params: &Handshake, key: &RsaPublicKey) -> Result<(), Error> {
+    match params.pinned {
+        Some(pinned) if pinned != fingerprint(key) => Err(Error::new(
+            ErrCode::Network,
+            format!("peer's identity key {} does not match the pinned one", fingerprint(key)))),
+        _ => Ok(()),
+    }
+}
+
+/// Padding used for every RSA encryption in the handshake
+fn padding() -> PaddingScheme {
+    PaddingScheme::new_oaep::<Sha256>()
+}
+
+/// Version information at the beginning of a handshake or deny payload;
+/// payloads without it are sent by version 1 peers
+fn peer_version(data: Option<&[u8]>) -> VersionInfo {
+    data.and_then(VersionInfo::peek)
+        .unwrap_or(VersionInfo { version: 1, min_version: 1, capabilities: Capabilities::NONE })
+}
+
+/// Checks that we can talk to a peer announcing `info`
+fn check_version(info: VersionInfo) -> Result<(), Error> {
+    if info.version < MIN_PROTO_VERSION {
+        return Err(Error::new(
+            ErrCode::Incompatible,
+            format!("peer runs incompatible simi version {}, version {MIN_PROTO_VERSION} or newer is required",
+                info.version)));
+    }
+    if info.min_version > PROTO_VERSION {
+        return Err(Error::new(
+            ErrCode::Incompatible,
+            format!("peer runs incompatible simi version {}, which requires version {} or newer (ours is {PROTO_VERSION})",
+                info.version, info.min_version)));
+    }
+    Ok(())
+}
+
+/// Handles a deny message recieved in response to our handshake message.
+///
+/// Returns `None` if the peer is not waiting for us or no longer wants the session.
+fn denied(reply: &Message, psk: bool) -> Result<Option<CryptoContext>, Error> {
+    if reply.deny_reason() != DenyReason::Busy {
+        return Err(rejected(reply, psk));
+    }
+    check_version(peer_version(reply.data.as_deref()))?;
+    debug_prompt("negative response. returning");
+    Ok(None)
+}
+
+/// Error reported when the peer has refused the handshake
+fn rejected(reply: &Message, psk: bool) -> Error {
+    if let Err(e) = check_version(peer_version(reply.data.as_deref())) {
+        return e;
+    }
+    let descr = match reply.deny_reason() {
+        DenyReason::PreSharedKey => "peer rejected the handshake, pre-shared keys differ or only one of you has one",
+        _ if psk => "peer rejected the handshake, pre-shared keys may differ",
+        _ => "peer rejected the handshake",
+    };
+    Error::new(ErrCode::Network, descr.to_owned())
+}
+
+/// Declines the peer's answer in place of our last message,
+/// as the session is no longer wanted
+fn withdraw(stream: &mut TcpStream, port: u16) -> Result<Option<CryptoContext>, Error> {
+    debug_prompt("handshake abandoned - declining");
+    send(stream, Message::new_deny(port, DenyReason::Busy))?;
+    Ok(None)
+}
+
+fn ill_formed() -> Error {
+    Error::new(ErrCode::Network, "ill-formed request".to_owned())
 }
 
 /// Write specified message into the stream
@@ -38,22 +378,47 @@ pub fn send(stream: &mut TcpStream, message: Message) -> Result<(), Error> {
 }
 
 
-pub fn send_secret(stream: &mut TcpStream, port: u16, text: &str, path: PathBuf, key: &Key<Aes128>) -> Result<(), Error> {
+/// Context of the signature of a signed secret
+const SECRET_CONTEXT: &[u8] = b"simi signed secret";
+
+/// A secret recieved from the peer
+pub struct Secret {
+    pub text: Zeroizing<String>,
+    /// Whether the text has been signed with the peer's identity key
+    pub signed: bool,
+}
+
+/// Identifier of a secret referred to by acknowledgements:
+/// a truncated hash of the image carrying it
+pub fn secret_id(image: &[u8]) -> u64 {
+    u64::from_le_bytes(Sha256::digest(image)[..8].try_into().unwrap())
+}
+
+/// Embeds `text` into the image at `path` to be sent to the peer.
+///
+/// If `signer` is given, the text is signed with it before encryption,
+/// so that the peer can check that it comes from our identity key.
+///
+/// Returns the identifier of the secret and the message carrying it.
+pub fn seal_secret(
+    port: u16,
+    text: &str,
+    path: PathBuf,
+    signer: Option<&RsaPrivateKey>,
+    ctx: &mut CryptoContext
+) -> Result<(u64, Message), Error> {
     let img = try_load_image(path)?;
-    let mut rng = rand::thread_rng();
-    let mut raw_nonce = [0u8; 12];
-    rng.fill(&mut raw_nonce);
-    let nonce = Nonce::from_slice(&raw_nonce[..12]);
-    let cipher = Aes128Gcm::new(key);
-    let mut ciphertext = cipher.encrypt(nonce, text.as_ref()).unwrap();
-    let mut payload = nonce.to_vec();
-    payload.append(&mut ciphertext);
+    let signature = signer
+        .map(|key| sign::sign(key, SECRET_CONTEXT, &[&ctx.session_id, text.as_bytes()]))
+        .transpose()?;
+    let body = SecretBody { text: text.to_owned(), signature }.serialize()?;
+    let payload = ctx.seal(Type::Speak, &body);
     let secret_image = lsb::embed(img, payload);
     let mut serialized_img: Vec<u8> = Vec::new();
     secret_image.write_to(&mut Cursor::new(&mut serialized_img), image::ImageOutputFormat::Png)
         .map_err(|e| convert_err(e, ErrCode::Serial))?;
-    send(stream, Message::new_speak(port, serialized_img))?;
-    Ok(())
+    let id = secret_id(&serialized_img);
+    Ok((id, Message::new_speak(port, serialized_img)))
 }
 
 fn try_load_image(supplied_path: PathBuf) -> Result<RgbImage, Error> {
@@ -80,15 +445,43 @@ fn try_load_image(supplied_path: PathBuf) -> Result<RgbImage, Error> {
     Ok(img)
 }
 
-pub fn decrypt_secret(secret: Vec<u8>, key: &Key<Aes128>) -> Result<String, Error> {
-    let secret_image = image::load_from_memory_with_format(&secret, image::ImageFormat::Png)
+/// Extracts and decrypts a secret sent by the peer.
+///
+/// A signed secret is rejected unless the signature matches
+/// the peer's identity key.
+pub fn decrypt_secret(secret: &[u8], ctx: &mut CryptoContext) -> Result<Secret, Error> {
+    let secret_image = image::load_from_memory_with_format(secret, image::ImageFormat::Png)
         .map_err(|e| convert_err(e, ErrCode::Serial))?.to_rgb8();
     let payload = lsb::extract(secret_image)?;
-    let cipher = Aes128Gcm::new(key);
-    let nonce = Nonce::from_slice(&payload[..12]);
-    let raw_text = cipher.decrypt(nonce, &payload[12..])
-        .map_err(|e| convert_err(e, ErrCode::Serial))?;
-    String::from_utf8(raw_text).map_err(|e| convert_err(e, ErrCode::Serial))
+    let raw_body = ctx.open(Type::Speak, &payload)?;
+    let mut body = SecretBody::deserialize(&raw_body)?;
+    if let Some(signature) = &body.signature {
+        sign::verify(&ctx.peer_public_key, SECRET_CONTEXT, &[&ctx.session_id, body.text.as_bytes()], signature)
+            .map_err(|_| Error::new(ErrCode::Network, "signature does not match the peer's identity key".to_owned()))?;
+    }
+    Ok(Secret {
+        text: Zeroizing::new(std::mem::take(&mut body.text)),
+        signed: body.signature.is_some(),
+    })
+}
+
+/// Message telling the peer whether the secret with identifier `id`
+/// has been decrypted
+pub fn acknowledgement(port: u16, id: u64, delivered: bool, ctx: &mut CryptoContext) -> Message {
+    if delivered {
+        Message::new_ack(port, ctx.seal(Type::Ack, &id.to_le_bytes()))
+    } else {
+        Message::new_nack(port, Some(ctx.seal(Type::Nack, &id.to_le_bytes())))
+    }
+}
+
+/// Opens an acknowledgement and returns the identifier
+/// of the secret it refers to
+pub fn open_acknowledgement(t: Type, sealed: &[u8], ctx: &mut CryptoContext) -> Result<u64, Error> {
+    let id = ctx.open(t, sealed)?;
+    let id = id.as_slice().try_into()
+        .map_err(|_| Error::new(ErrCode::Network, "ill-formed acknowledgement".to_owned()))?;
+    Ok(u64::from_le_bytes(id))
 }
 
 
@@ -97,107 +490,608 @@ pub fn recieve(stream: &mut TcpStream) -> Result<Message, Error> {
     Message::deserialize(stream)
 }
 
-/// Performs handshake and return `true` if connection has been established
-/// In future it should return session parameters (key and nonce)
-pub fn handshake_init(stream: &mut TcpStream, port: u16, private_key: &RsaPrivateKey) -> Result<Option<CryptoContext>, Error> {
-    let mut rng = thread_rng();
-    let public_key = RsaPublicKey::from(private_key);
-    let padding = PaddingScheme::new_pkcs1v15_encrypt();
+/// Why a handshake message has been refused: the error reported
+/// to the user and the reason the peer is given
+struct Refusal {
+    error: Error,
+    reason: DenyReason,
+}
+
+impl From<Error> for Refusal {
+    fn from(error: Error) -> Self {
+        Refusal { error, reason: DenyReason::Refused }
+    }
+}
+
+impl Refusal {
+    /// The pre-shared keys don't match
+    fn psk(error: Error) -> Self {
+        Refusal { error, reason: DenyReason::PreSharedKey }
+    }
+}
+
+/// Splits the outcome of checking a handshake message into the verdict
+/// passed to the state machine and whatever the check has produced
+fn judge<T>(checked: Result<T, Refusal>, valid: Verdict) -> (Result<Verdict, Refusal>, Option<T>) {
+    match checked {
+        Ok(val) => (Ok(valid), Some(val)),
+        Err(refusal) => (Err(refusal), None),
+    }
+}
+
+/// Where a handshake stands once a message has been handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Progress {
+    /// Our reply has been sent and the peer's next message is due
+    Continue,
+    /// The session is established
+    Connected,
+    /// The peer has denied the handshake
+    Denied,
+    /// The message comes from someone we don't wait for and has been declined
+    Declined,
+}
+
+/// A handshake with the peer in `state`, run by the state machine
+struct Exchange<'a> {
+    stream: &'a mut TcpStream,
+    port: u16,
+    state: State,
+    /// Address the peer's messages come from
+    from: SocketAddr,
+}
+
+impl<'a> Exchange<'a> {
+    /// Handshake over a connection we have opened
+    fn dialed(stream: &'a mut TcpStream, port: u16) -> Result<Self, Error> {
+        let peer = canonical(stream.peer_addr().map_err(|e| convert_err(e, ErrCode::Network))?);
+        Ok(Exchange { stream, port, state: State::Waiting(peer), from: peer })
+    }
+
+    /// Handshake over a connection whose first message is `request`,
+    /// while we are waiting for `desired`
+    fn answered(
+        connection: &'a mut (TcpStream, SocketAddr),
+        request: &Message,
+        desired: &SocketAddr,
+        port: u16
+    ) -> Self {
+        let from = canonical(announced(connection, request));
+        Exchange { stream: &mut connection.0, port, state: State::Waiting(canonical(*desired)), from }
+    }
+
+    /// Reads the peer's response to our message of type `sent`,
+    /// which has to be one the state machine awaits
+    fn response(&mut self, sent: Type) -> Result<Message, Error> {
+        let response = recieve(self.stream)?;
+        let awaited = state::awaited(&self.state, self.from, sent).unwrap_or_default();
+        if awaited.contains(&Some(response.t)) {
+            Ok(response)
+        } else {
+            Err(ill_formed())
+        }
+    }
+
+    /// Carries out the actions of the state machine on a message of type `t`.
+    ///
+    /// `checked` is the verdict on the message or the reason it is refused.
+    /// `reply` builds our reply of the given type and is only called
+    /// if the state machine sends one that is not a denial. A refused
+    /// message fails the handshake, after the peer has been told if
+    /// the state machine says so.
+    fn follow(
+        &mut self,
+        t: Type,
+        checked: Result<Verdict, Refusal>,
+        mut reply: impl FnMut(Type) -> Result<Message, Error>
+    ) -> Result<Progress, Error> {
+        let verdict = *checked.as_ref().unwrap_or(&Verdict::Invalid);
+        for action in state::transition(&self.state, self.from, t, verdict) {
+            match action {
+                Action::Reply(Type::Deny) => {
+                    // Only the peer we wait for is told why
+                    let refusal = checked.err().filter(|_| self.state.peer() == Some(self.from));
+                    let reason = refusal.as_ref().map_or(DenyReason::Busy, |refusal| refusal.reason);
+                    send(self.stream, Message::new_deny(self.port, reason))?;
+                    return refusal.map_or(Ok(Progress::Declined), |refusal| Err(refusal.error));
+                }
+                Action::Reply(Type::Nack) => send(self.stream, Message::new_nack(self.port, None))?,
+                Action::Reply(t) => match reply(t) {
+                    Ok(message) => send(self.stream, message)?,
+                    // We cannot go on, so the peer is not left waiting for our reply
+                    Err(e) => {
+                        send(self.stream, Message::new_deny(self.port, DenyReason::Refused))?;
+                        return Err(e);
+                    }
+                },
+                Action::Switch(State::Connected(_)) => return Ok(Progress::Connected),
+                Action::Display(Notice::Offline) => return Ok(Progress::Denied),
+                _ => (),
+            }
+        }
+        checked.map(|_| Progress::Continue).map_err(|refusal| refusal.error)
+    }
+}
+
+/// Performs handshake and returns session parameters
+/// if connection has been established
+pub fn handshake_init(stream: &mut TcpStream, params: &Handshake) -> Result<Option<CryptoContext>, Error> {
+    if params.mode == HandshakeMode::Noise {
+        return noise::initiate(stream, params);
+    }
+    let Handshake { port, private_key, suites, psk, .. } = *params;
+    let mut exchange = Exchange::dialed(stream, port)?;
 
     debug_prompt("initializing handshake...");
-    send(stream, Message::new_request(port, public_key))?;
+    send(exchange.stream, Message::new_request(port, RsaPublicKey::from(private_key), suites.to_vec()))?;
     debug_prompt("reading response");
-    let reply = Message::deserialize(stream)?;
-    if reply.t == Type::Accept && reply.data.is_some() {
-        let accept_data = AcceptPayload::deserialize(&reply.data.unwrap())?;
-        let r_key = 
-            RandAndKey::from_ciphertext(&private_key, padding, &accept_data.enc)?;
-        let padding = PaddingScheme::new_pkcs1v15_encrypt();
-        let confirm_data = 
-            accept_data.pkey.encrypt(&mut rng, padding, &r_key.serialize().unwrap()).unwrap();
-
-        debug_prompt("accepted - sending confirmation");
-        send(stream, Message::new_confirm(port, confirm_data))?;
-        let ctx = CryptoContext {
-            peer_public_key: accept_data.pkey,
-            session_key: *Key::<Aes128>::from_slice(&r_key.session_key),
-            nonce: r_key.nonce,
-        };
-        debug_prompt(&format!("Context: {:?}", ctx));
-        Ok(Some(ctx))
-    } else {
-        debug_prompt("negative response. returning");
-        Ok(None)
+    let reply = exchange.response(Type::Request)?;
+    if reply.t == Type::Deny {
+        exchange.follow(reply.t, Ok(Verdict::Valid), |_| Err(ill_formed()))?;
+        return denied(&reply, psk.is_some());
+    }
+    if params.abandoned() {
+        return withdraw(exchange.stream, port);
+    }
+    let (checked, established) = judge(confirmation(params, &reply), Verdict::Valid);
+    let (mut confirm, ctx) = established.unzip();
+    match exchange.follow(reply.t, checked, |_| confirm.take().ok_or_else(ill_formed))? {
+        Progress::Connected => {
+            debug_prompt(&format!("Context: {ctx:?}"));
+            Ok(ctx)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Checks the peer's accept and builds our confirmation
+/// along with the session it establishes
+fn confirmation(params: &Handshake, accept: &Message) -> Result<(Message, CryptoContext), Refusal> {
+    let Handshake { port, private_key, suites, psk, .. } = *params;
+    check_version(peer_version(accept.data.as_deref()))?;
+    let accept_data = AcceptPayload::deserialize(accept.data.as_deref().unwrap_or_default())
+        .map_err(|_| ill_formed())?;
+    check_peer_key(params, &accept_data.pkey)?;
+    if !suites.contains(&accept_data.suite) {
+        return Err(Error::new(
+            ErrCode::Network,
+            format!("peer chose cipher suite {:?} we do not support", accept_data.suite)).into());
     }
+    let mut r_key =
+        RandAndKey::from_ciphertext(private_key, padding(), &accept_data.enc)?;
+    if r_key.session_key.len() != accept_data.suite.key_len() {
+        return Err(Error::new(ErrCode::Network, "handshake failed".to_owned()).into());
+    }
+    let mixed_key = psk.map(|psk| psk::mix(&r_key.session_key, psk));
+    psk::verify(mixed_key.as_deref().map(Vec::as_slice), Role::Responder, &r_key.psk_proof)
+        .map_err(Refusal::psk)?;
+    let peer_ratchet_key = ratchet::public_key_from_slice(&r_key.ratchet_key)?;
+    let (ratchet_secret, ratchet_public) = ratchet::generate_keypair();
+    r_key.ratchet_key = ratchet_public.to_bytes().to_vec();
+    r_key.psk_proof = psk::proof(mixed_key.as_deref().map(Vec::as_slice), Role::Initiator);
+    let confirm_data =
+        accept_data.pkey.encrypt(&mut thread_rng(), padding(), &r_key.serialize().unwrap())
+            .map_err(|e| convert_err(e, ErrCode::Network))?;
+
+    debug_prompt("accepted - sending confirmation");
+    let session_key = mixed_key
+        .unwrap_or_else(|| Zeroizing::new(std::mem::take(&mut r_key.session_key)));
+    let ratchet = Ratchet::initiator(
+        accept_data.suite, &session_key, ratchet_secret, peer_ratchet_key);
+    let ctx = CryptoContext::new(
+        accept_data.pkey,
+        accept_data.suite,
+        session_key,
+        r_key.nonce,
+        Role::Initiator,
+        ratchet,
+        Negotiated { initiator: VersionInfo::ours(), responder: accept_data.info, offered: suites.to_vec() },
+    );
+    Ok((Message::new_confirm(port, confirm_data), ctx))
 }
 
 
-/// Try recieveng message; if it's a valid request,
-/// a decline message is sent back.
-pub fn decline(mut stream: TcpStream, port: u16) {
-    // TODO error handling
-    if let Ok(msg) = Message::deserialize(&mut stream) {
-        if msg.t == Type::Request {
-            send(&mut stream, Message::new_deny(port)).unwrap();
+/// Answers the first message `msg` of a connection the way the `Idle` state does:
+/// requests are declined and messages sent outside of a session
+/// are answered with a negative acknowledgement.
+pub fn decline(mut connection: (TcpStream, SocketAddr), msg: &Message, port: u16) {
+    for action in state::transition(&State::Idle, connection.1, msg.t, Verdict::Valid) {
+        let reply = match action {
+            Action::Reply(Type::Deny) => Message::new_deny(port, DenyReason::Busy),
+            Action::Reply(Type::Nack) => Message::new_nack(port, None),
+            _ => continue,
+        };
+        if let Err(e) = send(&mut connection.0, reply) {
+            debug_prompt(&format!("cannot decline connection from {}: {}", connection.1, e.descr));
         }
     }
 }
 
-/// Try recieving a message from `connection`; if it's a valid request,
-/// a valid response is sent.
+/// Handles `request`, the first message recieved from `connection`;
+/// if it's a valid request of either handshake, a valid response is sent.
+///
+/// On success the connection is kept open to carry the session.
 /// 
 /// Returns `true` if the request was accepted, `false` otherwise.
 pub fn accept_or_decline(
-    private_key: &RsaPrivateKey,
-    mut connection: (TcpStream, SocketAddr),
-    port: u16,
+    params: &Handshake,
+    request: &Message,
+    connection: &mut (TcpStream, SocketAddr),
     desired: &SocketAddr
 ) -> Result<Option<CryptoContext>, Error> {
-    let request = recieve(&mut connection.0)?;
-    if request.t == Type::Request && request.data.is_some() {
-        if request.port == desired.port() {
-            debug_prompt(&format!("incoming connection from {} - accepting", desired));
-            let mut rng = thread_rng();
-            let public_key = RsaPublicKey::from(private_key);
-            let padding = PaddingScheme::new_pkcs1v15_encrypt();
-            let nonce = rng.gen::<u64>();
-            let session_key = Aes128Gcm::generate_key(&mut rng);
-            let peer_public_key = 
-                RequestPayload::deserialize(&request.data.unwrap()).unwrap().pkey;
-            let rand_and_key = peer_public_key.encrypt(
-                &mut rng,
-                padding,
-                &RandAndKey {nonce, session_key: session_key.to_vec()}.serialize().unwrap())
-                .unwrap();
-            send(&mut connection.0, Message::new_accept(port, public_key, rand_and_key))?;
-            let response = recieve(&mut connection.0)?;
-            if response.t == Type::Confirm && response.data.is_some() {
-                debug_prompt("acception confirmed");
-                // check
-                let padding = PaddingScheme::new_pkcs1v15_encrypt();
-                let rand_and_key_check = 
-                    RandAndKey::from_ciphertext(&private_key, padding, &response.data.unwrap())?;
-
-                if rand_and_key_check.nonce != nonce
-                    && rand_and_key_check.session_key.as_slice() != session_key.as_slice() {
-                    return Err(Error::new(ErrCode::Network, "ill-formed request".to_owned()));
-                }
-                let ctx = CryptoContext {
-                    peer_public_key,
-                    session_key,
-                    nonce,
-                };
-                debug_prompt(&format!("Context: {:?}", ctx));
-                Ok(Some(ctx))
+    if request.t == Type::Noise {
+        return noise::respond(params, request, connection, desired);
+    }
+    let psk = params.psk;
+    let mut exchange = Exchange::answered(connection, request, desired, params.port);
+    let (checked, mut requested) = judge(requested(params, request), Verdict::Valid);
+    let mut offer = None;
+    let progress = exchange.follow(request.t, checked, |_| {
+        let (request_data, suite) = requested.take().ok_or_else(ill_formed)?;
+        debug_prompt(&format!("incoming connection from {desired} - accepting"));
+        debug_prompt(&format!("using cipher suite {suite:?}"));
+        let (accept, sent) = acceptance(params, request_data, suite)?;
+        offer = Some(sent);
+        Ok(accept)
+    })?;
+    let Some(offer) = offer else {
+        if progress == Progress::Declined {
+            debug_prompt(&format!("incoming connection from {} - declining", exchange.from));
+        }
+        return Ok(None);
+    };
+    let response = exchange.response(Type::Accept)?;
+    if response.t == Type::Deny {
+        exchange.follow(response.t, Ok(Verdict::Valid), |_| Err(ill_formed()))?;
+        return denied(&response, psk.is_some());
+    }
+    debug_prompt("acception confirmed");
+    let (checked, ctx) = judge(offer.confirmed(params.private_key, &response), Verdict::Valid);
+    match exchange.follow(response.t, checked, |_| Err(ill_formed()))? {
+        Progress::Connected => {
+            debug_prompt(&format!("Context: {ctx:?}"));
+            Ok(ctx)
+        }
+        _ => Err(ill_formed()),
+    }
+}
+
+/// Checks a handshake request and picks the cipher suite for the session
+fn requested(params: &Handshake, request: &Message) -> Result<(RequestPayload, CipherSuite), Refusal> {
+    check_version(peer_version(request.data.as_deref()))?;
+    let request_data = RequestPayload::deserialize(request.data.as_deref().unwrap_or_default())
+        .map_err(|_| ill_formed())?;
+    check_peer_key(params, &request_data.pkey)?;
+    let suite = cipher::negotiate(params.suites, &request_data.suites).ok_or_else(|| Error::new(
+        ErrCode::Network, "peer supports none of our cipher suites".to_owned()))?;
+    Ok((request_data, suite))
+}
+
+/// What the responder has put into its accept
+struct Offer {
+    request: RequestPayload,
+    suite: CipherSuite,
+    nonce: u64,
+    session_key: Zeroizing<Vec<u8>>,
+    mixed_key: Option<Zeroizing<Vec<u8>>>,
+    ratchet_secret: StaticSecret,
+}
+
+/// Builds the accept for a checked request
+fn acceptance(params: &Handshake, request: RequestPayload, suite: CipherSuite) -> Result<(Message, Offer), Error> {
+    let mut rng = thread_rng();
+    let nonce = rng.gen::<u64>();
+    let session_key = suite.generate_key();
+    let mixed_key = params.psk.map(|psk| psk::mix(&session_key, psk));
+    let (ratchet_secret, ratchet_public) = ratchet::generate_keypair();
+    let rand_and_key = request.pkey.encrypt(
+        &mut rng,
+        padding(),
+        &RandAndKey {
+            nonce,
+            session_key: session_key.to_vec(),
+            ratchet_key: ratchet_public.to_bytes().to_vec(),
+            psk_proof: psk::proof(mixed_key.as_deref().map(Vec::as_slice), Role::Responder),
+        }.serialize().unwrap())
+        .map_err(|e| convert_err(e, ErrCode::Network))?;
+    let accept = Message::new_accept(params.port, RsaPublicKey::from(params.private_key), suite, rand_and_key);
+    Ok((accept, Offer { request, suite, nonce, session_key, mixed_key, ratchet_secret }))
+}
+
+impl Offer {
+    /// Checks the initiator's confirmation of the offer
+    /// and returns the session it establishes
+    fn confirmed(self, private_key: &RsaPrivateKey, confirm: &Message) -> Result<CryptoContext, Refusal> {
+        let rand_and_key_check =
+            RandAndKey::from_ciphertext(private_key, padding(), confirm.data.as_deref().unwrap_or_default())?;
+        // Compared in constant time, so that timing reveals nothing of the key
+        let matches = rand_and_key_check.nonce.to_le_bytes().ct_eq(&self.nonce.to_le_bytes())
+            & rand_and_key_check.session_key.as_slice().ct_eq(self.session_key.as_slice());
+        if !bool::from(matches) {
+            return Err(ill_formed().into());
+        }
+        psk::verify(self.mixed_key.as_deref().map(Vec::as_slice), Role::Initiator, &rand_and_key_check.psk_proof)
+            .map_err(Refusal::psk)?;
+        let session_key = self.mixed_key.unwrap_or(self.session_key);
+        let peer_ratchet_key = ratchet::public_key_from_slice(&rand_and_key_check.ratchet_key)?;
+        let ratchet = Ratchet::responder(
+            self.suite, &session_key, &self.ratchet_secret, peer_ratchet_key);
+        Ok(CryptoContext::new(
+            self.request.pkey,
+            self.suite,
+            session_key,
+            self.nonce,
+            Role::Responder,
+            ratchet,
+            Negotiated {
+                initiator: self.request.info,
+                responder: VersionInfo::ours(),
+                offered: self.request.suites,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::TcpListener;
+    use std::sync::OnceLock;
+    use std::thread;
+    use std::time::Duration;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SUITES: &[CipherSuite] = &[CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+    /// Port the initiator announces
+    const PORT: u16 = 4242;
+
+    type Outcome = Result<Option<CryptoContext>, Error>;
+
+    /// Identity key of both peers; generating one is slow
+    pub fn identity() -> &'static RsaPrivateKey {
+        static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+        KEY.get_or_init(|| RsaPrivateKey::new(&mut thread_rng(), 2048).unwrap())
+    }
+
+    fn params(mode: HandshakeMode, psk: Option<&'static [u8]>) -> Handshake<'static> {
+        Handshake { mode, port: PORT, private_key: identity(), suites: SUITES, psk, pinned: None, abandoned: None }
+    }
+
+    /// Runs a handshake between two local peers.
+    ///
+    /// Returns the outcomes of the initiator and the responder.
+    pub fn handshake(initiator: Handshake<'static>, responder: &Handshake<'static>) -> (Outcome, Outcome) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let dialing = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            handshake_init(&mut stream, &initiator)
+        });
+        let (stream, from) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut connection = (stream, from);
+        let answered = recieve(&mut connection.0).and_then(|request| accept_or_decline(
+            responder, &request, &mut connection, &SocketAddr::new(from.ip(), PORT)));
+        // Let the initiator read our last message before it is closed
+        let dialed = dialing.join().unwrap();
+        (dialed, answered)
+    }
+
+    fn established((initiator, responder): (Outcome, Outcome)) -> (CryptoContext, CryptoContext) {
+        let initiator = initiator.unwrap().expect("initiator has not established the session");
+        let responder = responder.unwrap().expect("responder has not established the session");
+        (initiator, responder)
+    }
+
+    /// Checks that the handshake has failed on both sides
+    fn failed((initiator, responder): (Outcome, Outcome)) {
+        assert!(initiator.is_err(), "initiator has not failed");
+        assert!(!matches!(responder, Ok(Some(_))), "responder has established the session");
+    }
+
+    #[test]
+    fn pinned_handshake() {
+        let ours: &'static str = fingerprint(&RsaPublicKey::from(identity())).leak();
+        let pinned = |mode, pinned| Handshake { pinned: Some(pinned), ..params(mode, None) };
+        for mode in [HandshakeMode::Rsa, HandshakeMode::Noise] {
+            established(handshake(pinned(mode, ours), &pinned(mode, ours)));
+            // A fingerprint taken from another card, on either side
+            failed(handshake(pinned(mode, "SHA256:other"), &params(mode, None)));
+            let (initiator, responder) = handshake(params(mode, None), &pinned(mode, "SHA256:other"));
+            if mode == HandshakeMode::Rsa {
+                failed((initiator, responder));
             } else {
-                Err(Error::new(ErrCode::Network, "ill-formed request".to_owned()))
+                // Noise reveals the initiator's identity in the last message,
+                // so the initiator learns of the refusal only when the connection is closed
+                assert!(responder.is_err());
             }
-        } else {
-            debug_prompt(&format!("incoming connection from {}:{} - declining", connection.1.ip(), request.port));
-            send(&mut connection.0, Message::new_deny(port))?;
-            Ok(None)
         }
-    } else {
-        Err(Error::new(ErrCode::Network, "ill-formed request".to_owned()))
+    }
+
+    #[test]
+    fn abandoned_handshake() {
+        static ABANDONED: AtomicBool = AtomicBool::new(true);
+        for mode in [HandshakeMode::Rsa, HandshakeMode::Noise] {
+            let abandoned = Handshake { abandoned: Some(&ABANDONED), ..params(mode, None) };
+            // The initiator declines instead of confirming, so neither side
+            // takes the session for established, and neither has failed
+            let (initiator, responder) = handshake(abandoned, &params(mode, None));
+            assert!(matches!(initiator, Ok(None)), "initiator has not declined");
+            assert!(matches!(responder, Ok(None)), "responder has not taken the denial");
+        }
+    }
+
+    #[test]
+    fn small_peer_key() {
+        let small: &'static RsaPrivateKey = Box::leak(Box::new(RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap()));
+        let with_small = |mode| Handshake { private_key: small, ..params(mode, None) };
+        let too_small = |outcome: Outcome| assert!(outcome.is_err_and(|e| e.descr.contains("too small")));
+        for mode in [HandshakeMode::Rsa, HandshakeMode::Noise] {
+            // Refused by whoever gets the key, instead of failing to encrypt to it
+            let (_, responder) = handshake(with_small(mode), &params(mode, None));
+            too_small(responder);
+            let (initiator, _) = handshake(params(mode, None), &with_small(mode));
+            too_small(initiator);
+        }
+    }
+
+    #[test]
+    fn psk_handshake() {
+        for mode in [HandshakeMode::Rsa, HandshakeMode::Noise] {
+            let (initiator, responder) = established(handshake(
+                params(mode, Some(b"apple")), &params(mode, Some(b"apple"))));
+            assert_eq!(*initiator.session_key, *responder.session_key);
+            assert_eq!(initiator.session_id, responder.session_id);
+            let (without, _) = established(handshake(params(mode, None), &params(mode, None)));
+            assert_ne!(without.session_id, initiator.session_id);
+
+            failed(handshake(params(mode, Some(b"apple")), &params(mode, Some(b"pear"))));
+            failed(handshake(params(mode, None), &params(mode, Some(b"pear"))));
+            failed(handshake(params(mode, Some(b"apple")), &params(mode, None)));
+        }
+    }
+
+    /// Contexts of the initiator and the responder of one session
+    pub fn contexts() -> (CryptoContext, CryptoContext) {
+        let suite = CipherSuite::Aes256Gcm;
+        let session_key = Zeroizing::new(vec![3u8; suite.key_len()]);
+        let (initiator_secret, initiator_public) = ratchet::generate_keypair();
+        let (responder_secret, responder_public) = ratchet::generate_keypair();
+        let key = RsaPrivateKey::new(&mut thread_rng(), 512).unwrap();
+        let initiator = CryptoContext::new(
+            RsaPublicKey::from(&key), suite, session_key.clone(), 1, Role::Initiator,
+            Ratchet::initiator(suite, &session_key, initiator_secret, responder_public), negotiated(SUITES));
+        let responder = CryptoContext::new(
+            RsaPublicKey::from(&key), suite, session_key.clone(), 1, Role::Responder,
+            Ratchet::responder(suite, &session_key, &responder_secret, initiator_public), negotiated(SUITES));
+        (initiator, responder)
+    }
+
+    /// Fresh temporary directory holding a blank cover image, `cover.png`,
+    /// to hide secrets in; removed when dropped
+    pub fn assets() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        RgbImage::new(64, 64).save(dir.path().join("cover.png")).unwrap();
+        dir
+    }
+
+    fn negotiated(offered: &[CipherSuite]) -> Negotiated {
+        Negotiated { initiator: VersionInfo::ours(), responder: VersionInfo::ours(), offered: offered.to_vec() }
+    }
+
+    #[test]
+    fn session_id() {
+        let suite = CipherSuite::Aes256Gcm;
+        let session_key = Zeroizing::new(vec![3u8; suite.key_len()]);
+        let key = RsaPublicKey::from(identity());
+        let id = |suite: CipherSuite, negotiated: Negotiated| {
+            let (secret, public) = ratchet::generate_keypair();
+            let ratchet = Ratchet::initiator(suite, &session_key, secret, public);
+            CryptoContext::new(key.clone(), suite, session_key.clone(), 1, Role::Initiator, ratchet, negotiated)
+                .session_id
+        };
+        let original = id(suite, negotiated(SUITES));
+        assert_eq!(id(suite, negotiated(SUITES)), original);
+        // A suite removed from the offer or a reordered offer
+        assert_ne!(id(suite, negotiated(&SUITES[..1])), original);
+        assert_ne!(id(suite, negotiated(&[SUITES[1], SUITES[0]])), original);
+        // Another suite chosen from the same offer
+        assert_ne!(id(SUITES[1], negotiated(SUITES)), original);
+        // The offer is the one the initiator has made
+        let (initiator, responder) = established(handshake(
+            params(HandshakeMode::Rsa, None), &params(HandshakeMode::Rsa, None)));
+        assert_eq!(initiator.negotiated.offered, SUITES);
+        assert_eq!(responder.negotiated.offered, SUITES);
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let (mut initiator, mut responder) = contexts();
+        let sealed = initiator.seal(Type::SpeakSealed, b"hi");
+        // Reflected or retyped messages are rejected
+        assert!(initiator.open(Type::SpeakSealed, &sealed).is_err());
+        assert!(responder.open(Type::Close, &sealed).is_err());
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed).unwrap(), *b"hi");
+        assert!(responder.open(Type::SpeakSealed, &sealed).is_err());
+    }
+
+    #[test]
+    fn replay_window() {
+        let (mut initiator, mut responder) = contexts();
+        let sealed: Vec<_> = (0..70u8).map(|i| initiator.seal(Type::SpeakSealed, &[i])).collect();
+        // Out of order delivery within the window is fine, duplicates are not
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed[10]).unwrap(), [10]);
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed[5]).unwrap(), [5]);
+        assert!(responder.open(Type::SpeakSealed, &sealed[5]).is_err());
+        assert!(responder.open(Type::SpeakSealed, &sealed[10]).is_err());
+        // Messages more than 64 behind the newest one are stale
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed[69]).unwrap(), [69]);
+        assert!(responder.open(Type::SpeakSealed, &sealed[0]).is_err());
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed[6]).unwrap(), [6]);
+        // The counter is authenticated
+        let mut forged = sealed[7].clone();
+        forged[..8].copy_from_slice(&9u64.to_le_bytes());
+        assert!(responder.open(Type::SpeakSealed, &forged).is_err());
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed[8]).unwrap(), [8]);
+    }
+
+    #[test]
+    fn addresses() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        // Dual-stack sockets see IPv4 peers at mapped addresses
+        assert_eq!(canonical(addr("[::ffff:192.0.2.7]:1337")), addr("192.0.2.7:1337"));
+        assert!(same_addr(&addr("[::ffff:192.0.2.7]:1337"), &addr("192.0.2.7:1337")));
+        assert!(same_addr(&addr("192.0.2.7:1337"), &addr("[::ffff:192.0.2.7]:1337")));
+        // Plain IPv6 addresses, including IPv4-compatible ones, are left alone
+        assert_eq!(canonical(addr("[2001:db8::7]:1337")), addr("[2001:db8::7]:1337"));
+        assert!(same_addr(&addr("[2001:db8::7]:1337"), &addr("[2001:db8:0::7]:1337")));
+        assert!(!same_addr(&addr("[2001:db8::7]:1337"), &addr("[2001:db8::8]:1337")));
+        assert!(!same_addr(&addr("[::192.0.2.7]:1337"), &addr("192.0.2.7:1337")));
+        // Ports must match as well
+        assert!(!same_addr(&addr("192.0.2.7:1337"), &addr("192.0.2.7:1338")));
+        assert!(!same_addr(&addr("[::ffff:192.0.2.7]:1337"), &addr("192.0.2.7:1338")));
+        assert!(!same_addr(&addr("[2001:db8::7]:1337"), &addr("[2001:db8::7]:1338")));
+    }
+
+    /// Image carrying a sealed secret, as sent in a `Speak` message
+    fn carrying(payload: Vec<u8>) -> Vec<u8> {
+        let mut image = Vec::new();
+        lsb::embed(RgbImage::new(64, 64), payload)
+            .write_to(&mut Cursor::new(&mut image), image::ImageOutputFormat::Png)
+            .unwrap();
+        image
+    }
+
+    #[test]
+    fn signed_secret() {
+        let assets = assets();
+        let cover = assets.path().join("cover.png");
+        let (mut sender, mut receiver) = contexts();
+        receiver.peer_public_key = RsaPublicKey::from(identity());
+        let secret = |signer, sender: &mut CryptoContext| {
+            seal_secret(PORT, "meet at noon", cover.clone(), signer, sender).unwrap().1.data.unwrap()
+        };
+
+        let signed = decrypt_secret(&secret(Some(identity()), &mut sender), &mut receiver).unwrap();
+        assert!(signed.signed);
+        assert_eq!(signed.text.as_str(), "meet at noon");
+        assert!(!decrypt_secret(&secret(None, &mut sender), &mut receiver).unwrap().signed);
+        // Signed with a key other than the peer's identity key
+        let other = RsaPrivateKey::new(&mut thread_rng(), 1024).unwrap();
+        let forged = decrypt_secret(&secret(Some(&other), &mut sender), &mut receiver);
+        assert!(forged.is_err_and(|e| e.descr.contains("signature does not match")));
+        // Tampered with, or taken from another session
+        let mut signature = sign::sign(identity(), SECRET_CONTEXT, &[&sender.session_id, b"meet at noon"]).unwrap();
+        signature[0] ^= 1;
+        let other_session = sign::sign(identity(), SECRET_CONTEXT, &[&[0; 32], b"meet at noon"]).unwrap();
+        for signature in [signature, other_session] {
+            let body = SecretBody { text: "meet at noon".to_owned(), signature: Some(signature) }.serialize().unwrap();
+            let image = carrying(sender.seal(Type::Speak, &body));
+            assert!(decrypt_secret(&image, &mut receiver).is_err());
+        }
     }
 }
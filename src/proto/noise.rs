@@ -0,0 +1,306 @@
+//! Handshake based on the Noise Protocol Framework.
+//!
+//! An alternative to the Request/Accept/Confirm exchange. The peers run
+//! the `XX` pattern, which authenticates both static keys and hides them
+//! from passive observers. With a pre-shared key `XXpsk2` is used instead,
+//! so a mismatch is detected by the initiator on the second message.
+//!
+//! Noise static keys are derived from the RSA identity keys, and each peer
+//! signs its static key with its identity key, so the session is bound to
+//! the same identity as in the RSA handshake.
+use std::net::{SocketAddr, TcpStream};
+
+use bincode::{self, Options};
+use hkdf::Hkdf;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState};
+use zeroize::Zeroizing;
+
+use crate::core::debug_prompt;
+use crate::error::{Error, ErrCode, convert_err};
+use super::cipher::{self, CipherSuite};
+use super::message::{Message, Negotiated, NoisePayload, Type, VersionInfo};
+use super::ratchet::{self, Ratchet};
+use super::sign;
+use super::{send, peer_version, check_version, denied, withdraw, check_peer_key, CryptoContext, Handshake, Role};
+use super::{judge, Exchange, Progress, Refusal};
+use super::state::Verdict;
+
+const PATTERN: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+const PATTERN_PSK: &str = "Noise_XXpsk2_25519_ChaChaPoly_SHA256";
+
+/// Noise messages never exceed 65535 bytes
+const NOISE_MSG_LEN: usize = 65535;
+
+/// Payload of the first handshake message, sent in the clear
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    /// Cipher suites supported by the initiator
+    suites: Vec<CipherSuite>,
+}
+
+/// Payload of the second and the third handshake messages,
+/// encrypted by Noise
+#[derive(Serialize, Deserialize)]
+struct Identity {
+    /// Cipher suite chosen by the responder
+    suite: CipherSuite,
+    pkey: RsaPublicKey,
+    /// Signature of the sender's Noise static key made with `pkey`
+    signature: Vec<u8>,
+    /// Sender's initial ratchet public key
+    ratchet_key: Vec<u8>,
+}
+
+fn serializer() -> impl Options {
+    bincode::DefaultOptions::new().with_little_endian()
+}
+
+fn failed() -> Error {
+    Error::new(ErrCode::Network, "handshake failed".to_owned())
+}
+
+/// Derives the Noise static key from the identity key,
+/// so that it does not have to be stored separately
+fn static_key(private_key: &RsaPrivateKey) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let encoded = serializer()
+        .serialize(private_key)
+        .map(Zeroizing::new)
+        .map_err(|e| convert_err(e, ErrCode::Serial))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, &encoded)
+        .expand(b"simi noise static", key.as_mut())
+        .unwrap();
+    Ok(key)
+}
+
+/// Context of the signature binding a Noise static key to an identity key
+const IDENTITY_CONTEXT: &[u8] = b"simi noise identity";
+
+fn build(params: &Handshake, role: Role) -> Result<HandshakeState, Error> {
+    let pattern = if params.psk.is_some() { PATTERN_PSK } else { PATTERN };
+    let static_key = static_key(params.private_key)?;
+    let psk = params.psk.map(|raw| Zeroizing::new(<[u8; 32]>::from(Sha256::digest(raw))));
+    let mut builder = Builder::new(pattern.parse().unwrap())
+        .local_private_key(static_key.as_ref())
+        .map_err(|e| convert_err(e, ErrCode::Fatal))?;
+    if let Some(psk) = &psk {
+        builder = builder.psk(2, psk).map_err(|e| convert_err(e, ErrCode::Fatal))?;
+    }
+    match role {
+        Role::Initiator => builder.build_initiator(),
+        Role::Responder => builder.build_responder(),
+    }.map_err(|e| convert_err(e, ErrCode::Fatal))
+}
+
+fn write(state: &mut HandshakeState, params: &Handshake, payload: &[u8]) -> Result<Message, Error> {
+    let mut buf = vec![0u8; NOISE_MSG_LEN];
+    let len = state.write_message(payload, &mut buf)
+        .map_err(|e| convert_err(e, ErrCode::Serial))?;
+    buf.truncate(len);
+    Ok(Message::new_noise(params.port, params.psk.is_some(), buf))
+}
+
+fn read(state: &mut HandshakeState, payload: &NoisePayload) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let mut buf = Zeroizing::new(vec![0u8; NOISE_MSG_LEN]);
+    let len = state.read_message(&payload.handshake, &mut buf).map_err(|_| failed())?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Creates our identity payload, signing our Noise static key
+fn identity(params: &Handshake, suite: CipherSuite, ratchet_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let static_key = static_key(params.private_key)?;
+    let static_public = x25519_dalek::PublicKey::from(&x25519_dalek::StaticSecret::from(*static_key));
+    let signature = sign::sign(params.private_key, IDENTITY_CONTEXT, &[static_public.as_bytes()])?;
+    serializer()
+        .serialize(&Identity {
+            suite,
+            pkey: RsaPublicKey::from(params.private_key),
+            signature,
+            ratchet_key,
+        })
+        .map_err(|e| convert_err(e, ErrCode::Serial))
+}
+
+/// Parses the peer's identity payload and checks that its identity key
+/// signed the static key it used in the handshake
+fn verify_identity(state: &HandshakeState, raw: &[u8]) -> Result<Identity, Error> {
+    let identity: Identity = serializer().deserialize(raw).map_err(|_| failed())?;
+    let remote_static = state.get_remote_static().ok_or_else(failed)?;
+    sign::verify(&identity.pkey, IDENTITY_CONTEXT, &[remote_static], &identity.signature)
+        .map_err(|_| Error::new(ErrCode::Network, "peer's identity key does not match its handshake key".to_owned()))?;
+    Ok(identity)
+}
+
+/// Derives the session key and nonce from a completed handshake
+fn split(state: &mut HandshakeState, suite: CipherSuite) -> (Zeroizing<Vec<u8>>, u64) {
+    let (k1, k2) = state.dangerously_get_raw_split();
+    let mut ikm = Zeroizing::new(k1.to_vec());
+    ikm.extend_from_slice(&k2);
+    let hkdf = Hkdf::<Sha256>::new(Some(state.get_handshake_hash()), &ikm);
+    let mut session_key = Zeroizing::new(vec![0u8; suite.key_len()]);
+    hkdf.expand(b"simi noise session key", &mut session_key).unwrap();
+    let mut nonce = [0u8; 8];
+    hkdf.expand(b"simi noise nonce", &mut nonce).unwrap();
+    (session_key, u64::from_le_bytes(nonce))
+}
+
+/// Runs the initiator side of the Noise handshake
+pub fn initiate(stream: &mut TcpStream, params: &Handshake) -> Result<Option<CryptoContext>, Error> {
+    let mut exchange = Exchange::dialed(stream, params.port)?;
+    let mut state = build(params, Role::Initiator)?;
+    let hello = serializer()
+        .serialize(&Hello { suites: params.suites.to_vec() })
+        .map_err(|e| convert_err(e, ErrCode::Serial))?;
+    debug_prompt("initializing noise handshake...");
+    send(exchange.stream, write(&mut state, params, &hello)?)?;
+    debug_prompt("reading response");
+    let reply = exchange.response(Type::Noise)?;
+    if reply.t == Type::Deny {
+        exchange.follow(reply.t, Ok(Verdict::Valid), |_| Err(failed()))?;
+        return denied(&reply, params.psk.is_some());
+    }
+    if params.abandoned() {
+        return withdraw(exchange.stream, params.port);
+    }
+    let (checked, mut responded) = judge(check_response(&mut state, params, &reply), Verdict::ReplyAndFinish);
+    let mut confirmed = None;
+    let progress = exchange.follow(reply.t, checked, |_| {
+        let (peer, peer_info) = responded.take().ok_or_else(failed)?;
+        let (ratchet_secret, ratchet_public) = ratchet::generate_keypair();
+        let identity = identity(params, peer.suite, ratchet_public.to_bytes().to_vec())?;
+        debug_prompt("accepted - sending confirmation");
+        let message = write(&mut state, params, &identity)?;
+        confirmed = Some((peer, peer_info, ratchet_secret));
+        Ok(message)
+    })?;
+    let (Progress::Connected, Some((peer, peer_info, ratchet_secret))) = (progress, confirmed) else {
+        return Err(failed());
+    };
+    let peer_ratchet_key = ratchet::public_key_from_slice(&peer.ratchet_key)?;
+    let (session_key, nonce) = split(&mut state, peer.suite);
+    let ratchet = Ratchet::initiator(peer.suite, &session_key, ratchet_secret, peer_ratchet_key);
+    let negotiated = Negotiated { initiator: VersionInfo::ours(), responder: peer_info, offered: params.suites.to_vec() };
+    let ctx = CryptoContext::new(peer.pkey, peer.suite, session_key, nonce, Role::Initiator, ratchet, negotiated);
+    debug_prompt(&format!("Context: {ctx:?}"));
+    Ok(Some(ctx))
+}
+
+/// Checks the responder's message, returning its identity and version
+fn check_response(
+    state: &mut HandshakeState,
+    params: &Handshake,
+    reply: &Message
+) -> Result<(Identity, VersionInfo), Refusal> {
+    check_version(peer_version(reply.data.as_deref()))?;
+    let payload = NoisePayload::deserialize(reply.data.as_deref().unwrap_or_default())?;
+    let raw_identity = read(state, &payload).map_err(|e| match params.psk {
+        // With a pre-shared key, this is how a mismatch shows up
+        Some(_) => Refusal::psk(Error::new(ErrCode::Network, "handshake failed, pre-shared keys may differ".to_owned())),
+        None => e.into(),
+    })?;
+    let peer = verify_identity(state, &raw_identity)?;
+    check_peer_key(params, &peer.pkey)?;
+    if !params.suites.contains(&peer.suite) {
+        return Err(Error::new(
+            ErrCode::Network,
+            format!("peer chose cipher suite {:?} we do not support", peer.suite)).into());
+    }
+    Ok((peer, payload.info))
+}
+
+/// Runs the responder side of the Noise handshake, `request` being
+/// the first message recieved from `connection`
+pub fn respond(
+    params: &Handshake,
+    request: &Message,
+    connection: &mut (TcpStream, SocketAddr),
+    desired: &SocketAddr
+) -> Result<Option<CryptoContext>, Error> {
+    let mut exchange = Exchange::answered(connection, request, desired, params.port);
+    let mut state = build(params, Role::Responder)?;
+    let (checked, mut greeted) = judge(check_hello(&mut state, params, request), Verdict::Reply);
+    let mut sent = None;
+    let progress = exchange.follow(request.t, checked, |_| {
+        let (peer_info, hello, suite) = greeted.take().ok_or_else(failed)?;
+        debug_prompt(&format!("incoming noise handshake from {desired} - accepting"));
+        debug_prompt(&format!("using cipher suite {suite:?}"));
+        let (ratchet_secret, ratchet_public) = ratchet::generate_keypair();
+        let identity = identity(params, suite, ratchet_public.to_bytes().to_vec())?;
+        let message = write(&mut state, params, &identity)?;
+        sent = Some((peer_info, hello, suite, ratchet_secret));
+        Ok(message)
+    })?;
+    let Some((peer_info, hello, suite, ratchet_secret)) = sent else {
+        if progress == Progress::Declined {
+            debug_prompt(&format!("incoming connection from {} - declining", exchange.from));
+        }
+        return Ok(None);
+    };
+
+    let response = exchange.response(Type::Noise)?;
+    if response.t == Type::Deny {
+        exchange.follow(response.t, Ok(Verdict::Valid), |_| Err(failed()))?;
+        return denied(&response, params.psk.is_some());
+    }
+    debug_prompt("acception confirmed");
+    let (checked, peer) = judge(check_confirmation(&mut state, params, &response, suite), Verdict::Valid);
+    let (Progress::Connected, Some(peer)) = (exchange.follow(response.t, checked, |_| Err(failed()))?, peer) else {
+        return Err(failed());
+    };
+    let peer_ratchet_key = ratchet::public_key_from_slice(&peer.ratchet_key)?;
+    let (session_key, nonce) = split(&mut state, suite);
+    let ratchet = Ratchet::responder(suite, &session_key, &ratchet_secret, peer_ratchet_key);
+    let negotiated = Negotiated { initiator: peer_info, responder: VersionInfo::ours(), offered: hello.suites };
+    let ctx = CryptoContext::new(peer.pkey, suite, session_key, nonce, Role::Responder, ratchet, negotiated);
+    debug_prompt(&format!("Context: {ctx:?}"));
+    Ok(Some(ctx))
+}
+
+/// Checks the initiator's first message and picks the cipher suite
+fn check_hello(
+    state: &mut HandshakeState,
+    params: &Handshake,
+    request: &Message
+) -> Result<(VersionInfo, Hello, CipherSuite), Refusal> {
+    check_version(peer_version(request.data.as_deref()))?;
+    let payload = NoisePayload::deserialize(request.data.as_deref().unwrap_or_default()).map_err(|_| failed())?;
+    if payload.psk != params.psk.is_some() {
+        return Err(Refusal::psk(psk_mismatch(payload.psk)));
+    }
+    let hello: Hello = serializer()
+        .deserialize(&read(state, &payload)?)
+        .map_err(|_| failed())?;
+    let suite = cipher::negotiate(params.suites, &hello.suites).ok_or_else(|| Error::new(
+        ErrCode::Network, "peer supports none of our cipher suites".to_owned()))?;
+    Ok((payload.info, hello, suite))
+}
+
+/// Checks the initiator's last message, returning its identity
+fn check_confirmation(
+    state: &mut HandshakeState,
+    params: &Handshake,
+    response: &Message,
+    suite: CipherSuite
+) -> Result<Identity, Refusal> {
+    let payload = NoisePayload::deserialize(response.data.as_deref().unwrap_or_default())?;
+    let raw_identity = read(state, &payload)?;
+    let peer = verify_identity(state, &raw_identity)?;
+    check_peer_key(params, &peer.pkey)?;
+    if peer.suite != suite {
+        return Err(Error::new(ErrCode::Network, "ill-formed request".to_owned()).into());
+    }
+    Ok(peer)
+}
+
+fn psk_mismatch(peer_has_psk: bool) -> Error {
+    let descr = if peer_has_psk {
+        "peer expects a pre-shared key, but none is configured for them"
+    } else {
+        "peer has no pre-shared key configured for us"
+    };
+    Error::new(ErrCode::Network, descr.to_owned())
+}
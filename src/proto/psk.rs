@@ -0,0 +1,83 @@
+//! Pre-shared key support.
+//!
+//! When both peers have a pre-shared key configured for each other,
+//! it is mixed into the session key negotiated in the handshake.
+//! Each side proves knowledge of the resulting key, so a mismatch
+//! is detected before any message is sent.
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::error::{Error, ErrCode};
+use super::Role;
+
+/// Derives the session key from the negotiated one and the pre-shared key
+pub fn mix(session_key: &[u8], psk: &[u8]) -> Zeroizing<Vec<u8>> {
+    let mut mixed = Zeroizing::new(vec![0u8; session_key.len()]);
+    Hkdf::<Sha256>::new(Some(psk), session_key)
+        .expand(b"simi psk", &mut mixed)
+        .unwrap();
+    mixed
+}
+
+fn mac(key: &[u8], sender: Role) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(b"simi psk proof");
+    mac.update(&[sender as u8]);
+    mac
+}
+
+/// Computes a proof of knowledge of the mixed session key.
+///
+/// Returns an empty vector if there is no pre-shared key.
+pub fn proof(mixed_key: Option<&[u8]>, sender: Role) -> Vec<u8> {
+    match mixed_key {
+        Some(key) => mac(key, sender).finalize().into_bytes().to_vec(),
+        None => Vec::new(),
+    }
+}
+
+/// Checks the proof sent by the peer
+pub fn verify(mixed_key: Option<&[u8]>, sender: Role, proof: &[u8]) -> Result<(), Error> {
+    match (mixed_key, proof.is_empty()) {
+        (None, true) => Ok(()),
+        (None, false) => Err(Error::new(
+            ErrCode::Network,
+            "peer expects a pre-shared key, but none is configured for them".to_owned())),
+        (Some(_), true) => Err(Error::new(
+            ErrCode::Network,
+            "peer has no pre-shared key configured for us".to_owned())),
+        (Some(key), false) => mac(key, sender)
+            .verify_slice(proof)
+            .map_err(|_| Error::new(ErrCode::Network, "pre-shared keys differ".to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION_KEY: [u8; 32] = [5; 32];
+
+    #[test]
+    fn same_keys() {
+        assert_eq!(*mix(&SESSION_KEY, b"apple"), *mix(&SESSION_KEY, b"apple"));
+        assert_ne!(*mix(&SESSION_KEY, b"apple"), *mix(&SESSION_KEY, b"pear"));
+        assert_ne!(mix(&SESSION_KEY, b"apple").as_slice(), SESSION_KEY);
+    }
+
+    #[test]
+    fn proofs() {
+        let ours = mix(&SESSION_KEY, b"apple");
+        let theirs = mix(&SESSION_KEY, b"pear");
+        let proof_of = |key: Option<&[u8]>| proof(key, Role::Initiator);
+        assert!(verify(Some(&ours), Role::Initiator, &proof_of(Some(&ours))).is_ok());
+        assert!(verify(None, Role::Initiator, &proof_of(None)).is_ok());
+        // A proof is bound to the sender
+        assert!(verify(Some(&ours), Role::Responder, &proof_of(Some(&ours))).is_err());
+        assert!(verify(Some(&ours), Role::Initiator, &proof_of(Some(&theirs))).is_err());
+        assert!(verify(Some(&ours), Role::Initiator, &proof_of(None)).is_err());
+        assert!(verify(None, Role::Initiator, &proof_of(Some(&theirs))).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Heartbeat authentication.
+//!
+//! Heartbeats go on while the session keys are busy with a secret,
+//! so they are not sealed with them. Each heartbeat carries a counter
+//! and a MAC under a key derived from the session key when the session
+//! starts, so a forged or replayed one cannot keep a session alive
+//! once the peer is gone.
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+use crate::error::{Error, ErrCode};
+use super::Role;
+
+/// Heartbeats of both directions in a session
+pub struct Pulse {
+    key: Zeroizing<[u8; 32]>,
+    /// Our side of the handshake
+    role: Role,
+    /// Counter of the last heartbeat sent
+    sent: u64,
+    /// Counter of the last heartbeat recieved
+    recieved: u64,
+}
+
+impl Pulse {
+    pub fn new(session_key: &[u8], session_id: &[u8], role: Role) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(session_id), session_key)
+            .expand(b"simi heartbeat", key.as_mut())
+            .unwrap();
+        Self { key, role, sent: 0, recieved: 0 }
+    }
+
+    fn mac(&self, sender: Role, counter: u64) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.key.as_ref()).unwrap();
+        mac.update(&[sender as u8]);
+        mac.update(&counter.to_le_bytes());
+        mac
+    }
+
+    /// Payload of our next heartbeat: its counter followed by the MAC
+    pub fn beat(&mut self) -> Vec<u8> {
+        self.sent += 1;
+        let mut payload = self.sent.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.mac(self.role, self.sent).finalize().into_bytes());
+        payload
+    }
+
+    /// Checks the payload of a heartbeat of the peer.
+    ///
+    /// Heartbeats arrive in order, so one whose counter is not past
+    /// the last accepted one has been replayed.
+    pub fn check(&mut self, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() < 8 {
+            return Err(Error::new(ErrCode::Serial, "truncated heartbeat".to_owned()));
+        }
+        let (counter, tag) = payload.split_at(8);
+        let counter = u64::from_le_bytes(counter.try_into().unwrap());
+        if counter <= self.recieved {
+            return Err(Error::new(ErrCode::Network, "replayed heartbeat".to_owned()));
+        }
+        self.mac(self.role.peer(), counter)
+            .verify_slice(tag)
+            .map_err(|_| Error::new(ErrCode::Network, "heartbeat failed authentication".to_owned()))?;
+        self.recieved = counter;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = &[7; 32];
+    const ID: &[u8] = &[1; 16];
+
+    #[test]
+    fn heartbeats() {
+        let mut initiator = Pulse::new(KEY, ID, Role::Initiator);
+        let mut responder = Pulse::new(KEY, ID, Role::Responder);
+        let first = initiator.beat();
+        let second = initiator.beat();
+        responder.check(&first).unwrap();
+        responder.check(&second).unwrap();
+        // Replayed, reflected and forged heartbeats are rejected
+        assert!(responder.check(&first).is_err());
+        let own = initiator.beat();
+        assert!(initiator.check(&own).is_err());
+        let mut forged = responder.beat();
+        forged[0] = 9;
+        assert!(initiator.check(&forged).is_err());
+        assert!(initiator.check(&[]).is_err());
+        // Another session's heartbeats are rejected too
+        let mut other = Pulse::new(KEY, &[2; 16], Role::Responder);
+        assert!(initiator.check(&other.beat()).is_err());
+    }
+}
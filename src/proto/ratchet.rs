@@ -0,0 +1,421 @@
+//! Double ratchet used to encrypt secret messages.
+//!
+//! Every secret is encrypted with a fresh message key taken from a
+//! symmetric KDF chain, and the chains are reseeded with a new X25519
+//! exchange each time the direction of the conversation changes.
+//! A leaked message key reveals only one message; a leaked state is
+//! healed by the next DH step.
+use std::collections::HashMap;
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::{Error, ErrCode};
+use super::cipher::{CipherSuite, NONCE_LEN};
+
+/// Length of a serialized `Header`
+pub const HEADER_LEN: usize = 40;
+
+/// Maximum number of message keys skipped in a single chain.
+/// Protects from being forced to derive an arbitrary amount of keys.
+const MAX_SKIP: u32 = 1000;
+
+/// Header prepended to every ratchet-encrypted message.
+///
+/// It is sent in plain, but authenticated as associated data.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    /// Sender's current ratchet public key
+    pub dh: [u8; 32],
+    /// Number of messages in the sender's previous sending chain
+    pub pn: u32,
+    /// Number of this message in the current sending chain
+    pub n: u32,
+}
+
+impl Header {
+    pub fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..32].copy_from_slice(&self.dh);
+        bytes[32..36].copy_from_slice(&self.pn.to_le_bytes());
+        bytes[36..].copy_from_slice(&self.n.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::new(ErrCode::Serial, "truncated ratchet header".to_owned()));
+        }
+        let mut dh = [0u8; 32];
+        dh.copy_from_slice(&bytes[..32]);
+        let pn = u32::from_le_bytes(bytes[32..36].try_into().unwrap());
+        let n = u32::from_le_bytes(bytes[36..HEADER_LEN].try_into().unwrap());
+        Ok(Self { dh, pn, n })
+    }
+}
+
+/// State of the double ratchet for one session.
+///
+/// All keys are wiped from memory when the state is dropped.
+#[derive(Clone)]
+pub struct Ratchet {
+    suite: CipherSuite,
+    root_key: [u8; 32],
+    dh_self: StaticSecret,
+    dh_remote: PublicKey,
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    skipped: HashMap<([u8; 32], u32), [u8; 32]>,
+}
+
+impl std::fmt::Debug for Ratchet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ratchet")
+            .field("suite", &self.suite)
+            .field("ns", &self.ns)
+            .field("nr", &self.nr)
+            .field("pn", &self.pn)
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.root_key.zeroize();
+        self.send_chain.zeroize();
+        self.recv_chain.zeroize();
+        self.skipped.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// Generates a new ratchet key pair
+pub fn generate_keypair() -> (StaticSecret, PublicKey) {
+    let secret = StaticSecret::random_from_rng(thread_rng());
+    let public = PublicKey::from(&secret);
+    (secret, public)
+}
+
+/// Parses a public key received from the peer
+pub fn public_key_from_slice(bytes: &[u8]) -> Result<PublicKey, Error> {
+    let raw: [u8; 32] = bytes.try_into()
+        .map_err(|_| Error::new(ErrCode::Network, "ill-formed ratchet key".to_owned()))?;
+    Ok(PublicKey::from(raw))
+}
+
+/// Root KDF: mixes a DH output into the root key and returns
+/// the new root key and a new chain key
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(root_key), dh_out)
+        .expand(b"simi ratchet root", okm.as_mut())
+        .unwrap();
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+/// Chain KDF: advances the chain key and returns it along with a message key
+fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let derive = |label: u8| {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).unwrap();
+        mac.update(&[label]);
+        let out: [u8; 32] = mac.finalize().into_bytes().into();
+        out
+    };
+    (derive(2), derive(1))
+}
+
+fn seal(suite: CipherSuite, message_key: &[u8; 32], header: &[u8], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill(&mut nonce);
+    let aad = [header, aad].concat();
+    let mut ciphertext = suite.encrypt(message_key, &nonce, plaintext, &aad);
+    let mut result = header.to_vec();
+    result.extend_from_slice(&nonce);
+    result.append(&mut ciphertext);
+    result
+}
+
+fn open(suite: CipherSuite, message_key: &[u8; 32], header: &[u8], body: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    if body.len() < NONCE_LEN {
+        return Err(Error::new(ErrCode::Serial, "truncated secret".to_owned()));
+    }
+    let nonce = body[..NONCE_LEN].try_into().unwrap();
+    let aad = [header, aad].concat();
+    suite.decrypt(message_key, nonce, &body[NONCE_LEN..], &aad)
+}
+
+impl Ratchet {
+    /// Creates the ratchet on the side that initiated the handshake.
+    ///
+    /// `remote` is the ratchet key received in the `Accept` message,
+    /// `dh_self` is the key pair whose public part is sent in `Confirm`.
+    pub fn initiator(suite: CipherSuite, session_key: &[u8], dh_self: StaticSecret, remote: PublicKey) -> Self {
+        let root_key = Self::initial_root(session_key);
+        let (root_key, send_chain) =
+            kdf_root(&root_key, dh_self.diffie_hellman(&remote).as_bytes());
+        Self {
+            suite,
+            root_key,
+            dh_self,
+            dh_remote: remote,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: HashMap::new(),
+        }
+    }
+
+    /// Creates the ratchet on the side that accepted the handshake.
+    ///
+    /// `dh_self` is the key pair sent in `Accept`, `remote` is the
+    /// ratchet key received in `Confirm`. The responder performs the first
+    /// DH step at once, so both sides are able to send immediately.
+    pub fn responder(suite: CipherSuite, session_key: &[u8], dh_self: &StaticSecret, remote: PublicKey) -> Self {
+        let root_key = Self::initial_root(session_key);
+        let (root_key, recv_chain) =
+            kdf_root(&root_key, dh_self.diffie_hellman(&remote).as_bytes());
+        let (dh_self, _) = generate_keypair();
+        let (root_key, send_chain) =
+            kdf_root(&root_key, dh_self.diffie_hellman(&remote).as_bytes());
+        Self {
+            suite,
+            root_key,
+            dh_self,
+            dh_remote: remote,
+            send_chain: Some(send_chain),
+            recv_chain: Some(recv_chain),
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            skipped: HashMap::new(),
+        }
+    }
+
+    fn initial_root(session_key: &[u8]) -> [u8; 32] {
+        let mut root = [0u8; 32];
+        Hkdf::<Sha256>::new(None, session_key)
+            .expand(b"simi ratchet init", &mut root)
+            .unwrap();
+        root
+    }
+
+    /// Encrypts `plaintext` with the next message key.
+    /// `aad` is authenticated along with the header, but not included
+    /// in the output.
+    ///
+    /// Returns serialized header followed by nonce and ciphertext.
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        if self.send_chain.is_none() {
+            // We have just received from a new chain; start a new one of our own
+            let (dh_self, _) = generate_keypair();
+            let (root_key, send_chain) =
+                kdf_root(&self.root_key, dh_self.diffie_hellman(&self.dh_remote).as_bytes());
+            self.dh_self = dh_self;
+            self.root_key = root_key;
+            self.send_chain = Some(send_chain);
+        }
+        let (chain, message_key) = kdf_chain(&self.send_chain.unwrap());
+        let message_key = Zeroizing::new(message_key);
+        self.send_chain = Some(chain);
+        let header = Header {
+            dh: PublicKey::from(&self.dh_self).to_bytes(),
+            pn: self.pn,
+            n: self.ns,
+        };
+        self.ns += 1;
+        seal(self.suite, &message_key, &header.to_bytes(), plaintext, aad)
+    }
+
+    /// Decrypts a message produced by the peer's `encrypt`.
+    ///
+    /// Messages may arrive out of order: keys of skipped messages are
+    /// kept until those messages arrive. The state is left untouched
+    /// if the message cannot be authenticated.
+    pub fn decrypt(&mut self, payload: &[u8], aad: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let header = Header::from_bytes(payload)?;
+        let (raw_header, body) = payload.split_at(HEADER_LEN);
+        if let Some(message_key) = self.skipped.get(&(header.dh, header.n)) {
+            let plaintext = open(self.suite, message_key, raw_header, body, aad)?;
+            if let Some(mut message_key) = self.skipped.remove(&(header.dh, header.n)) {
+                message_key.zeroize();
+            }
+            return Ok(Zeroizing::new(plaintext));
+        }
+        let mut next = self.clone();
+        if header.dh != next.dh_remote.to_bytes() {
+            next.skip_keys(header.pn)?;
+            next.dh_step(PublicKey::from(header.dh));
+        }
+        next.skip_keys(header.n)?;
+        // The initiator has no receiving chain until the peer's first DH step
+        let Some(chain) = next.recv_chain else {
+            return Err(Error::new(ErrCode::Serial, "message from a chain not started yet".to_owned()));
+        };
+        let (chain, message_key) = kdf_chain(&chain);
+        let message_key = Zeroizing::new(message_key);
+        next.recv_chain = Some(chain);
+        next.nr += 1;
+        let plaintext = open(next.suite, &message_key, raw_header, body, aad)?;
+        *self = next;
+        Ok(Zeroizing::new(plaintext))
+    }
+
+    /// Stores keys of the messages in the current receiving chain
+    /// up to (but not including) message number `until`
+    fn skip_keys(&mut self, until: u32) -> Result<(), Error> {
+        let Some(mut chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until > self.nr.saturating_add(MAX_SKIP)
+            || self.skipped.len() + (until.saturating_sub(self.nr) as usize) > MAX_SKIP as usize {
+            return Err(Error::new(ErrCode::Serial, "too many skipped messages".to_owned()));
+        }
+        let dh = self.dh_remote.to_bytes();
+        while self.nr < until {
+            let (next, message_key) = kdf_chain(&chain);
+            self.skipped.insert((dh, self.nr), message_key);
+            chain = next;
+            self.nr += 1;
+        }
+        self.recv_chain = Some(chain);
+        Ok(())
+    }
+
+    /// Switches to the new receiving chain announced by the peer.
+    /// Our own sending chain is restarted lazily on the next `encrypt`.
+    fn dh_step(&mut self, remote: PublicKey) {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dh_remote = remote;
+        let (root_key, recv_chain) =
+            kdf_root(&self.root_key, self.dh_self.diffie_hellman(&remote).as_bytes());
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUITE: CipherSuite = CipherSuite::Aes256Gcm;
+    const AAD: &[u8] = b"aad";
+
+    /// Ratchets of the initiator and the responder of one session
+    fn pair() -> (Ratchet, Ratchet) {
+        let session_key = [7u8; 32];
+        let (initiator_secret, initiator_public) = generate_keypair();
+        let (responder_secret, responder_public) = generate_keypair();
+        let initiator = Ratchet::initiator(SUITE, &session_key, initiator_secret, responder_public);
+        let responder = Ratchet::responder(SUITE, &session_key, &responder_secret, initiator_public);
+        (initiator, responder)
+    }
+
+    fn header(payload: &[u8]) -> Header {
+        Header::from_bytes(payload).unwrap()
+    }
+
+    #[test]
+    fn in_order() {
+        let (mut alice, mut bob) = pair();
+        for i in 0..3u8 {
+            let sealed = alice.encrypt(&[i], AAD);
+            assert_eq!(header(&sealed).n, u32::from(i));
+            assert_eq!(*bob.decrypt(&sealed, AAD).unwrap(), [i]);
+        }
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let (mut alice, mut bob) = pair();
+        let sealed: Vec<_> = (0..4u8).map(|i| alice.encrypt(&[i], AAD)).collect();
+        assert_eq!(*bob.decrypt(&sealed[3], AAD).unwrap(), [3]);
+        assert_eq!(bob.skipped.len(), 3);
+        for i in [1u8, 0, 2] {
+            assert_eq!(*bob.decrypt(&sealed[usize::from(i)], AAD).unwrap(), [i]);
+        }
+        assert!(bob.skipped.is_empty());
+        // A skipped key is used only once
+        assert!(bob.decrypt(&sealed[1], AAD).is_err());
+    }
+
+    #[test]
+    fn too_many_skipped() {
+        let (mut alice, mut bob) = pair();
+        let sealed: Vec<_> = (0..=MAX_SKIP + 1).map(|_| alice.encrypt(b"x", AAD)).collect();
+        assert!(bob.decrypt(&sealed[MAX_SKIP as usize + 1], AAD).is_err());
+        assert!(bob.skipped.is_empty());
+        assert!(bob.decrypt(&sealed[MAX_SKIP as usize], AAD).is_ok());
+        assert_eq!(bob.skipped.len(), MAX_SKIP as usize);
+    }
+
+    #[test]
+    fn tampered() {
+        let (mut alice, mut bob) = pair();
+        let sealed = alice.encrypt(b"secret", AAD);
+        let mut bad_header = sealed.clone();
+        bad_header[32] ^= 1;
+        let mut bad_body = sealed.clone();
+        *bad_body.last_mut().unwrap() ^= 1;
+        for payload in [bad_header, bad_body, sealed[..HEADER_LEN].to_vec()] {
+            assert!(bob.decrypt(&payload, AAD).is_err());
+        }
+        assert!(bob.decrypt(&sealed, b"other aad").is_err());
+        // The state is left untouched by the failures
+        assert!(bob.skipped.is_empty());
+        assert_eq!(*bob.decrypt(&sealed, AAD).unwrap(), *b"secret");
+    }
+
+    #[test]
+    fn initial_remote_key() {
+        let (mut alice, _) = pair();
+        // The responder's initial ratchet key opens no chain of the initiator
+        let forged = Header { dh: alice.dh_remote.to_bytes(), pn: 0, n: 0 };
+        let payload = [forged.to_bytes().as_slice(), &[0u8; 64]].concat();
+        assert!(alice.decrypt(&payload, AAD).is_err());
+        assert!(alice.recv_chain.is_none());
+    }
+
+    #[test]
+    fn dh_steps() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.encrypt(b"1", AAD);
+        let second = alice.encrypt(b"2", AAD);
+        assert_eq!(header(&first).dh, header(&second).dh);
+        bob.decrypt(&first, AAD).unwrap();
+        bob.decrypt(&second, AAD).unwrap();
+
+        // Each change of direction brings a new ratchet key
+        let reply = bob.encrypt(b"3", AAD);
+        assert_eq!(*alice.decrypt(&reply, AAD).unwrap(), *b"3");
+        let next = alice.encrypt(b"4", AAD);
+        assert_ne!(header(&next).dh, header(&first).dh);
+        assert_ne!(header(&next).dh, header(&reply).dh);
+        assert_eq!((header(&next).pn, header(&next).n), (2, 0));
+        assert_eq!(*bob.decrypt(&next, AAD).unwrap(), *b"4");
+
+        // A message of the replaced chain is still opened with a skipped key
+        let late = alice.encrypt(b"5", AAD);
+        let reply = bob.encrypt(b"6", AAD);
+        alice.decrypt(&reply, AAD).unwrap();
+        let after = alice.encrypt(b"7", AAD);
+        assert_eq!(*bob.decrypt(&after, AAD).unwrap(), *b"7");
+        assert_eq!(*bob.decrypt(&late, AAD).unwrap(), *b"5");
+    }
+}
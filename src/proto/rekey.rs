@@ -0,0 +1,143 @@
+//! Key update within an established session.
+//!
+//! Either peer may send a `Rekey` message carrying a fresh X25519 key,
+//! sealed with the current keys. The other peer replies with a key
+//! of its own and both derive new session keys from the current ones
+//! and the DH output. If both peers start a key update at the same time,
+//! each takes the other's request as the reply, which yields the same keys.
+use std::time::Duration;
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::error::Error;
+use super::message::{Capabilities, Type};
+use super::ratchet::{self, Ratchet};
+use super::CryptoContext;
+
+impl CryptoContext {
+    /// Returns `true` if the current keys have been used for more than
+    /// `max_messages` messages or are older than `max_age`.
+    /// Zero disables the respective limit. Never due if the peer
+    /// does not support key updates.
+    pub fn rekey_due(&self, max_messages: u64, max_age: Duration) -> bool {
+        !self.rekey_pending()
+            && self.capabilities().contains(Capabilities::REKEY)
+            && ((max_messages != 0 && self.messages >= max_messages)
+                || (!max_age.is_zero() && self.established.elapsed() >= max_age))
+    }
+
+    /// Returns `true` while our key update waits for the peer's reply
+    pub fn rekey_pending(&self) -> bool {
+        self.pending_rekey.is_some()
+    }
+
+    /// Starts a key update and returns the payload of the `Rekey` message.
+    ///
+    /// Returns `None` if a key update is already in progress.
+    pub fn start_rekey(&mut self) -> Option<Vec<u8>> {
+        if self.pending_rekey.is_some() {
+            return None;
+        }
+        let (secret, public) = ratchet::generate_keypair();
+        self.pending_rekey = Some(secret);
+        Some(self.seal(Type::Rekey, public.as_bytes()))
+    }
+
+    /// Abandons our key update, e.g. if the request could not be sent
+    pub fn cancel_rekey(&mut self) {
+        self.pending_rekey = None;
+    }
+
+    /// Handles a `Rekey` message from the peer and switches to the new keys.
+    ///
+    /// If the message is a request rather than an answer to ours,
+    /// returns the payload of the answer, which must be sent to the peer.
+    /// It is sealed with the replaced keys, as the peer is still using them.
+    pub fn handle_rekey(&mut self, payload: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let raw_key = self.open(Type::Rekey, payload)?;
+        let remote = ratchet::public_key_from_slice(&raw_key)?;
+        if let Some(secret) = self.pending_rekey.take() {
+            self.switch_keys(secret, remote);
+            return Ok(None);
+        }
+        let (secret, public) = ratchet::generate_keypair();
+        let reply = self.seal(Type::Rekey, public.as_bytes());
+        self.switch_keys(secret, remote);
+        Ok(Some(reply))
+    }
+
+    /// Derives new keys and replaces the context with them,
+    /// keeping the current one to open messages still in flight
+    fn switch_keys(&mut self, secret: StaticSecret, remote: PublicKey) {
+        let shared = secret.diffie_hellman(&remote);
+        let hkdf = Hkdf::<Sha256>::new(Some(&self.session_key), shared.as_bytes());
+        let mut session_key = Zeroizing::new(vec![0u8; self.suite.key_len()]);
+        hkdf.expand(b"simi rekey session key", &mut session_key).unwrap();
+        let mut nonce = [0u8; 8];
+        hkdf.expand(b"simi rekey nonce", &mut nonce).unwrap();
+        // The keys play the part of the initial ratchet keys;
+        // the lower one takes the initiator's side
+        let public = PublicKey::from(&secret);
+        let ratchet = if public.as_bytes() < remote.as_bytes() {
+            Ratchet::initiator(self.suite, &session_key, secret, remote)
+        } else {
+            Ratchet::responder(self.suite, &session_key, &secret, remote)
+        };
+        let next = CryptoContext::new(
+            self.peer_public_key.clone(),
+            self.suite,
+            session_key,
+            u64::from_le_bytes(nonce),
+            self.role,
+            ratchet,
+            self.negotiated.clone(),
+        );
+        let mut previous = std::mem::replace(self, next);
+        previous.previous = None;
+        previous.pending_rekey = None;
+        self.previous = Some(Box::new(previous));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::message::Type;
+    use crate::proto::tests::contexts;
+
+    #[test]
+    fn simultaneous() {
+        let (mut initiator, mut responder) = contexts();
+        let ours = initiator.start_rekey().unwrap();
+        let theirs = responder.start_rekey().unwrap();
+        // A message sealed with the old keys is still in flight
+        let late = initiator.seal(Type::SpeakSealed, b"late");
+        assert!(initiator.handle_rekey(&theirs).unwrap().is_none());
+        assert!(responder.handle_rekey(&ours).unwrap().is_none());
+
+        assert_eq!(*initiator.session_key, *responder.session_key);
+        assert_eq!(initiator.session_id, responder.session_id);
+        assert_eq!(*responder.open(Type::SpeakSealed, &late).unwrap(), *b"late");
+        let sealed = initiator.seal(Type::SpeakSealed, b"to responder");
+        assert_eq!(*responder.open(Type::SpeakSealed, &sealed).unwrap(), *b"to responder");
+        let sealed = responder.seal(Type::SpeakSealed, b"to initiator");
+        assert_eq!(*initiator.open(Type::SpeakSealed, &sealed).unwrap(), *b"to initiator");
+    }
+
+    #[test]
+    fn request_and_reply() {
+        let (mut initiator, mut responder) = contexts();
+        let request = initiator.start_rekey().unwrap();
+        assert!(initiator.rekey_pending());
+        assert!(initiator.start_rekey().is_none());
+        let reply = responder.handle_rekey(&request).unwrap().unwrap();
+        assert!(!responder.rekey_pending());
+        assert!(initiator.handle_rekey(&reply).unwrap().is_none());
+        assert!(!initiator.rekey_pending());
+        assert_eq!(*initiator.session_key, *responder.session_key);
+        let sealed = responder.seal(Type::SpeakSealed, b"new keys");
+        assert_eq!(*initiator.open(Type::SpeakSealed, &sealed).unwrap(), *b"new keys");
+    }
+}
@@ -0,0 +1,108 @@
+//! Replay protection for messages received within a session.
+use crate::error::{Error, ErrCode};
+
+/// Number of counters behind the highest one that are still accepted
+const WINDOW_SIZE: u64 = 64;
+
+/// Sliding window over message counters.
+///
+/// Counters start from 1. A message is rejected if its counter has
+/// already been seen or is too far behind the highest counter seen,
+/// while moderate reordering is tolerated.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayWindow {
+    /// Highest counter accepted so far, 0 if none
+    highest: u64,
+    /// Bit `i` is set if counter `highest - i` has been accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Checks that a message with `counter` may be accepted
+    /// without modifying the window
+    pub fn check(&self, counter: u64) -> Result<(), Error> {
+        if counter == 0 {
+            return Err(Error::new(ErrCode::Network, "invalid message counter".to_owned()));
+        }
+        if counter > self.highest {
+            return Ok(());
+        }
+        let offset = self.highest - counter;
+        if offset >= WINDOW_SIZE {
+            Err(Error::new(ErrCode::Network, "stale message rejected".to_owned()))
+        } else if self.seen & (1 << offset) != 0 {
+            Err(Error::new(ErrCode::Network, "replayed message rejected".to_owned()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Marks `counter` as seen. Must be called only after the message
+    /// has passed `check` and has been authenticated.
+    pub fn accept(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= WINDOW_SIZE { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = counter;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(counters: &[u64]) -> ReplayWindow {
+        let mut window = ReplayWindow::default();
+        for &counter in counters {
+            window.check(counter).unwrap();
+            window.accept(counter);
+        }
+        window
+    }
+
+    #[test]
+    fn duplicate() {
+        let window = window(&[1, 2, 3]);
+        for counter in [1, 2, 3] {
+            assert!(window.check(counter).is_err());
+        }
+        assert!(window.check(4).is_ok());
+        assert!(window.check(0).is_err());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let window = window(&[5, 2, 4, 1, 3]);
+        for counter in 1..=5 {
+            assert!(window.check(counter).is_err());
+        }
+        assert!(window.check(6).is_ok());
+    }
+
+    #[test]
+    fn edge() {
+        let highest = WINDOW_SIZE + 10;
+        let mut window = window(&[highest]);
+        // The oldest counter still inside the window and the first one outside it
+        assert!(window.check(highest - WINDOW_SIZE + 1).is_ok());
+        assert!(window.check(highest - WINDOW_SIZE).is_err());
+        window.accept(highest - WINDOW_SIZE + 1);
+        assert!(window.check(highest - WINDOW_SIZE + 1).is_err());
+
+        // Sliding by one pushes that counter out, the others are still remembered
+        window.check(highest + 1).unwrap();
+        window.accept(highest + 1);
+        assert!(window.check(highest - WINDOW_SIZE + 2).is_ok());
+        assert!(window.check(highest).is_err());
+
+        // Sliding by the whole window forgets everything behind it
+        let far = highest + 1 + WINDOW_SIZE;
+        window.accept(far);
+        assert!(window.check(far - 1).is_ok());
+        assert!(window.check(highest + 1).is_err());
+    }
+}
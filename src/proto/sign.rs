@@ -0,0 +1,29 @@
+//! Signatures made with identity keys (RSA-PSS with SHA-256).
+//!
+//! Every signed statement starts with a context string, so that
+//! a signature made for one purpose cannot be passed off for another.
+use rand::thread_rng;
+use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, ErrCode, convert_err};
+
+fn digest(context: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha256::new_with_prefix(context);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Signs the concatenation of `parts` under `context`
+pub fn sign(key: &RsaPrivateKey, context: &[u8], parts: &[&[u8]]) -> Result<Vec<u8>, Error> {
+    key.sign_with_rng(&mut thread_rng(), PaddingScheme::new_pss::<Sha256>(), &digest(context, parts))
+        .map_err(|e| convert_err(e, ErrCode::Fatal))
+}
+
+/// Checks a signature made by `sign` with the same `context` and `parts`
+pub fn verify(key: &RsaPublicKey, context: &[u8], parts: &[&[u8]], signature: &[u8]) -> Result<(), Error> {
+    key.verify(PaddingScheme::new_pss::<Sha256>(), &digest(context, parts), signature)
+        .map_err(|_| Error::new(ErrCode::Network, "invalid signature".to_owned()))
+}
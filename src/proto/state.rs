@@ -0,0 +1,380 @@
+//! The protocol state machine described in Proto.md.
+//!
+//! Transitions are pure functions of the current state, the sender
+//! and the type of a message, so they don't depend on sockets. Everything
+//! the state machine needs to know about the contents of a message is
+//! reduced to a `Verdict`, which the caller obtains by authenticating
+//! and decrypting the message beforehand.
+use std::net::SocketAddr;
+
+use super::message::Type;
+
+/// State of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// In the menu
+    Idle,
+    /// Waiting for the given peer to connect
+    Waiting(SocketAddr),
+    /// Connected to the given peer
+    Connected(SocketAddr),
+}
+
+impl State {
+    /// The peer we are waiting for or connected to
+    pub fn peer(&self) -> Option<SocketAddr> {
+        match *self {
+            State::Idle => None,
+            State::Waiting(peer) | State::Connected(peer) => Some(peer),
+        }
+    }
+}
+
+/// Outcome of checking a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message is valid and needs no reply of its own.
+    /// For a handshake message, it is the last one
+    Valid,
+    /// The message is valid and the sender waits for a message
+    /// of the same type in return: a Noise handshake message
+    /// or a key update request
+    Reply,
+    /// The message is valid and the handshake is finished
+    /// as soon as the reply is sent: the second Noise message
+    ReplyAndFinish,
+    /// The message failed authentication, decryption or key pinning
+    Invalid,
+}
+
+impl Verdict {
+    fn is_valid(self) -> bool {
+        self != Verdict::Invalid
+    }
+}
+
+/// Something shown to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notice {
+    /// The peer we are waiting for is offline
+    Offline,
+    /// Text of a plain or sealed message
+    Message,
+    /// Text of a secret
+    Secret,
+    /// Our secret has been decrypted by the peer
+    Delivered,
+    /// The peer failed to decrypt our secret
+    Undelivered,
+    /// The peer we are connected to has stopped responding
+    WentOffline,
+}
+
+/// Response to a message, in the order it should be carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Send a message of the given type to the sender
+    Reply(Type),
+    /// Switch to the given state
+    Switch(State),
+    /// Show a notice to the user
+    Display(Notice),
+    /// Replace the session keys
+    UpdateKeys,
+}
+
+/// Responses a sent message waits for; `None` stands for no response
+pub type Responses = &'static [Option<Type>];
+
+const NOTHING: Responses = &[None];
+
+/// Actions to take in `state` on a message of type `t` sent from `from`
+pub fn transition(state: &State, from: SocketAddr, t: Type, verdict: Verdict) -> Vec<Action> {
+    match *state {
+        State::Waiting(peer) if peer == from => waiting(peer, t, verdict),
+        State::Connected(peer) if peer == from => connected(peer, t, verdict),
+        // Idle or a message from someone we don't talk to
+        _ => match t {
+            Type::Request | Type::Noise => vec![Action::Reply(Type::Deny)],
+            Type::Speak | Type::SpeakPlain | Type::SpeakSealed => vec![Action::Reply(Type::Nack)],
+            _ => Vec::new(),
+        },
+    }
+}
+
+fn waiting(peer: SocketAddr, t: Type, verdict: Verdict) -> Vec<Action> {
+    let connected = Action::Switch(State::Connected(peer));
+    match (t, verdict) {
+        (Type::Request | Type::Accept | Type::Noise, Verdict::Invalid) => vec![Action::Reply(Type::Deny)],
+        (Type::Request, _) => vec![Action::Reply(Type::Accept)],
+        (Type::Accept, _) => vec![Action::Reply(Type::Confirm), connected],
+        (Type::Confirm, verdict) if verdict.is_valid() => vec![connected],
+        (Type::Noise, Verdict::Reply) => vec![Action::Reply(Type::Noise)],
+        (Type::Noise, Verdict::ReplyAndFinish) => vec![Action::Reply(Type::Noise), connected],
+        (Type::Noise, _) => vec![connected],
+        (Type::Deny, _) => vec![Action::Display(Notice::Offline)],
+        (Type::Speak | Type::SpeakPlain | Type::SpeakSealed, _) => vec![Action::Reply(Type::Nack)],
+        _ => Vec::new(),
+    }
+}
+
+fn connected(peer: SocketAddr, t: Type, verdict: Verdict) -> Vec<Action> {
+    match (t, verdict) {
+        (Type::Speak, Verdict::Invalid) => vec![Action::Reply(Type::Nack)],
+        (Type::Speak, _) => vec![Action::Display(Notice::Secret), Action::Reply(Type::Ack)],
+        (Type::SpeakPlain, _) => vec![Action::Display(Notice::Message)],
+        (_, Verdict::Invalid) => Vec::new(),
+        (Type::SpeakSealed, _) => vec![Action::Display(Notice::Message)],
+        (Type::Rekey, Verdict::Reply) => vec![Action::UpdateKeys, Action::Reply(Type::Rekey)],
+        (Type::Rekey, _) => vec![Action::UpdateKeys],
+        (Type::Ack, _) => vec![Action::Display(Notice::Delivered)],
+        (Type::Nack, _) => vec![Action::Display(Notice::Undelivered)],
+        (Type::Close, _) => vec![Action::Switch(State::Waiting(peer))],
+        _ => Vec::new(),
+    }
+}
+
+/// Actions to take in `state` when nothing has been heard from the peer
+/// for longer than the heartbeat timeout
+pub fn timed_out(state: &State) -> Vec<Action> {
+    match *state {
+        State::Connected(peer) => vec![Action::Display(Notice::WentOffline), Action::Switch(State::Waiting(peer))],
+        _ => Vec::new(),
+    }
+}
+
+/// Responses a message of type `t` sent to `to` in `state` waits for,
+/// or `None` if such a message is never sent
+pub fn awaited(state: &State, to: SocketAddr, t: Type) -> Option<Responses> {
+    match *state {
+        State::Waiting(peer) if peer == to => match t {
+            Type::Request => Some(&[Some(Type::Accept), Some(Type::Deny)]),
+            Type::Accept => Some(&[Some(Type::Confirm), Some(Type::Deny)]),
+            Type::Noise => Some(&[Some(Type::Noise), Some(Type::Deny)]),
+            Type::Deny => Some(NOTHING),
+            _ => None,
+        },
+        State::Connected(peer) if peer == to => match t {
+            Type::Speak => Some(&[Some(Type::Ack), Some(Type::Nack)]),
+            Type::Rekey => Some(&[Some(Type::Rekey), None]),
+            Type::Confirm | Type::Noise | Type::SpeakPlain | Type::SpeakSealed
+                | Type::Ack | Type::Nack | Type::Close | Type::Heartbeat => Some(NOTHING),
+            _ => None,
+        },
+        // Only denials are sent to anyone but the peer
+        _ => matches!(t, Type::Deny | Type::Nack).then_some(NOTHING),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VERDICTS: [Verdict; 4] = [Verdict::Valid, Verdict::Reply, Verdict::ReplyAndFinish, Verdict::Invalid];
+
+    fn x() -> SocketAddr {
+        "10.0.0.1:1337".parse().unwrap()
+    }
+
+    fn y() -> SocketAddr {
+        "10.0.0.2:1337".parse().unwrap()
+    }
+
+    /// Columns of the response table
+    fn columns() -> [State; 5] {
+        [State::Idle, State::Waiting(x()), State::Waiting(y()), State::Connected(x()), State::Connected(y())]
+    }
+
+    /// Row of the response table for a message from `x`
+    fn row(t: Type, verdict: Verdict) -> Vec<Vec<Action>> {
+        columns().iter().map(|state| transition(state, x(), t, verdict)).collect()
+    }
+
+    fn reply(t: Type) -> Vec<Action> {
+        vec![Action::Reply(t)]
+    }
+
+    fn display(notice: Notice) -> Vec<Action> {
+        vec![Action::Display(notice)]
+    }
+
+    #[test]
+    fn request() {
+        for verdict in VERDICTS {
+            let waiting = if verdict == Verdict::Invalid { Type::Deny } else { Type::Accept };
+            assert_eq!(row(Type::Request, verdict), [
+                reply(Type::Deny), reply(waiting), reply(Type::Deny), vec![], reply(Type::Deny)]);
+        }
+    }
+
+    #[test]
+    fn accept() {
+        for verdict in VERDICTS {
+            let waiting = if verdict == Verdict::Invalid {
+                reply(Type::Deny)
+            } else {
+                vec![Action::Reply(Type::Confirm), Action::Switch(State::Connected(x()))]
+            };
+            assert_eq!(row(Type::Accept, verdict), [vec![], waiting, vec![], vec![], vec![]]);
+        }
+    }
+
+    #[test]
+    fn confirm() {
+        for verdict in VERDICTS {
+            let waiting = if verdict == Verdict::Invalid {
+                vec![]
+            } else {
+                vec![Action::Switch(State::Connected(x()))]
+            };
+            assert_eq!(row(Type::Confirm, verdict), [vec![], waiting, vec![], vec![], vec![]]);
+        }
+    }
+
+    #[test]
+    fn noise() {
+        let connected = Action::Switch(State::Connected(x()));
+        for (verdict, waiting) in [
+            (Verdict::Reply, reply(Type::Noise)),
+            (Verdict::ReplyAndFinish, vec![Action::Reply(Type::Noise), connected]),
+            (Verdict::Valid, vec![connected]),
+            (Verdict::Invalid, reply(Type::Deny)),
+        ] {
+            assert_eq!(row(Type::Noise, verdict), [
+                reply(Type::Deny), waiting, reply(Type::Deny), vec![], reply(Type::Deny)]);
+        }
+    }
+
+    #[test]
+    fn deny() {
+        for verdict in VERDICTS {
+            assert_eq!(row(Type::Deny, verdict), [vec![], display(Notice::Offline), vec![], vec![], vec![]]);
+        }
+    }
+
+    #[test]
+    fn speak() {
+        for verdict in VERDICTS {
+            let connected = if verdict == Verdict::Invalid {
+                reply(Type::Nack)
+            } else {
+                vec![Action::Display(Notice::Secret), Action::Reply(Type::Ack)]
+            };
+            assert_eq!(row(Type::Speak, verdict), [
+                reply(Type::Nack), reply(Type::Nack), reply(Type::Nack), connected, reply(Type::Nack)]);
+        }
+    }
+
+    #[test]
+    fn speak_plain() {
+        for verdict in VERDICTS {
+            assert_eq!(row(Type::SpeakPlain, verdict), [
+                reply(Type::Nack), reply(Type::Nack), reply(Type::Nack), display(Notice::Message), reply(Type::Nack)]);
+        }
+    }
+
+    #[test]
+    fn speak_sealed() {
+        for verdict in VERDICTS {
+            let connected = if verdict == Verdict::Invalid { vec![] } else { display(Notice::Message) };
+            assert_eq!(row(Type::SpeakSealed, verdict), [
+                reply(Type::Nack), reply(Type::Nack), reply(Type::Nack), connected, reply(Type::Nack)]);
+        }
+    }
+
+    #[test]
+    fn rekey() {
+        for (verdict, connected) in [
+            (Verdict::Reply, vec![Action::UpdateKeys, Action::Reply(Type::Rekey)]),
+            (Verdict::Valid, vec![Action::UpdateKeys]),
+            (Verdict::Invalid, vec![]),
+        ] {
+            assert_eq!(row(Type::Rekey, verdict), [vec![], vec![], vec![], connected, vec![]]);
+        }
+    }
+
+    #[test]
+    fn ack() {
+        for verdict in VERDICTS {
+            let connected = if verdict == Verdict::Invalid { vec![] } else { display(Notice::Delivered) };
+            assert_eq!(row(Type::Ack, verdict), [vec![], vec![], vec![], connected, vec![]]);
+        }
+    }
+
+    #[test]
+    fn nack() {
+        for verdict in VERDICTS {
+            let connected = if verdict == Verdict::Invalid { vec![] } else { display(Notice::Undelivered) };
+            assert_eq!(row(Type::Nack, verdict), [vec![], vec![], vec![], connected, vec![]]);
+        }
+    }
+
+    #[test]
+    fn close() {
+        for verdict in VERDICTS {
+            let connected = if verdict == Verdict::Invalid {
+                vec![]
+            } else {
+                vec![Action::Switch(State::Waiting(x()))]
+            };
+            assert_eq!(row(Type::Close, verdict), [vec![], vec![], vec![], connected, vec![]]);
+        }
+    }
+
+    #[test]
+    fn heartbeat() {
+        for verdict in VERDICTS {
+            assert_eq!(row(Type::Heartbeat, verdict), [vec![], vec![], vec![], vec![], vec![]]);
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let went_offline = |peer| vec![Action::Display(Notice::WentOffline), Action::Switch(State::Waiting(peer))];
+        assert_eq!(columns().map(|state| timed_out(&state)), [vec![], vec![], vec![], went_offline(x()), went_offline(y())]);
+    }
+
+    /// Row of the await table: responses awaited in Waiting(x) and Connected(x)
+    fn awaited_row(t: Type, to: SocketAddr) -> [Option<Responses>; 2] {
+        [awaited(&State::Waiting(x()), to, t), awaited(&State::Connected(x()), to, t)]
+    }
+
+    #[test]
+    fn await_table() {
+        let accept_or_deny: Responses = &[Some(Type::Accept), Some(Type::Deny)];
+        let confirm_or_deny: Responses = &[Some(Type::Confirm), Some(Type::Deny)];
+        let noise_or_deny: Responses = &[Some(Type::Noise), Some(Type::Deny)];
+        let ack_or_nack: Responses = &[Some(Type::Ack), Some(Type::Nack)];
+        let rekey_or_nothing: Responses = &[Some(Type::Rekey), None];
+        for (t, expected) in [
+            (Type::Request, [Some(accept_or_deny), None]),
+            (Type::Accept, [Some(confirm_or_deny), None]),
+            (Type::Confirm, [None, Some(NOTHING)]),
+            (Type::Noise, [Some(noise_or_deny), Some(NOTHING)]),
+            (Type::Deny, [Some(NOTHING), None]),
+            (Type::Speak, [None, Some(ack_or_nack)]),
+            (Type::SpeakPlain, [None, Some(NOTHING)]),
+            (Type::SpeakSealed, [None, Some(NOTHING)]),
+            (Type::Rekey, [None, Some(rekey_or_nothing)]),
+            (Type::Ack, [None, Some(NOTHING)]),
+            (Type::Nack, [None, Some(NOTHING)]),
+            (Type::Close, [None, Some(NOTHING)]),
+            (Type::Heartbeat, [None, Some(NOTHING)]),
+        ] {
+            assert_eq!(awaited_row(t, x()), expected, "{t:?} -> x");
+        }
+    }
+
+    #[test]
+    fn await_table_other_peers() {
+        for t in [Type::Deny, Type::Nack] {
+            assert_eq!(awaited_row(t, y()), [Some(NOTHING), Some(NOTHING)], "{t:?} -> y");
+            assert_eq!(awaited(&State::Idle, x(), t), Some(NOTHING), "{t:?} in Idle");
+        }
+        for t in [Type::Request, Type::Accept, Type::Confirm, Type::Noise, Type::Speak,
+                  Type::SpeakPlain, Type::SpeakSealed, Type::Rekey, Type::Ack, Type::Close, Type::Heartbeat] {
+            assert_eq!(awaited_row(t, y()), [None, None], "{t:?} -> y");
+            assert_eq!(awaited(&State::Idle, x(), t), None, "{t:?} in Idle");
+        }
+    }
+}